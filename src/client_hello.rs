@@ -0,0 +1,344 @@
+use std::ops::Range;
+
+use anyhow::{Context, Error};
+
+pub const RECORD_HEADER_LEN: usize = 5;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+
+pub const EXT_SERVER_NAME: u16 = 0x0000;
+pub const EXT_SUPPORTED_GROUPS: u16 = 0x000a;
+pub const EXT_EC_POINT_FORMATS: u16 = 0x000b;
+pub const EXT_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+pub const EXT_ALPN: u16 = 0x0010;
+pub const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+
+/// The fields of a TLS ClientHello that are relevant for fingerprinting and for finding the SNI.
+///
+/// All ranges are byte offsets into the buffer that was passed to [`ClientHello::parse`], which
+/// starts with the TLS record header.
+#[derive(Debug, Clone, Default)]
+pub struct ClientHello {
+    /// Length of the entire TLS record, including its header.
+    pub record_len: usize,
+    pub version: u16,
+    pub cipher_suites: Vec<u16>,
+    /// Extension types, in the order they were sent.
+    pub extensions: Vec<u16>,
+    pub supported_groups: Vec<u16>,
+    pub point_formats: Vec<u8>,
+    pub signature_algorithms: Vec<u16>,
+    pub supported_versions: Vec<u16>,
+    pub alpn: Vec<Vec<u8>>,
+    /// The hostname inside the server_name extension.
+    pub server_name: Option<Range<usize>>,
+    /// The entire server_name extension, including its type and length.
+    pub server_name_extension: Option<Range<usize>>,
+}
+
+/// GREASE values (RFC 8701) are random placeholders that clients send to keep servers tolerant,
+/// and need to be ignored for fingerprinting.
+pub fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+/// If `buf` starts with a TLS handshake record, return the length of the entire record including
+/// its header. Returns `None` if not enough bytes are available yet to tell.
+pub fn record_len(buf: &[u8]) -> Option<Result<usize, Error>> {
+    if buf.is_empty() {
+        return None;
+    }
+
+    if buf[0] != CONTENT_TYPE_HANDSHAKE {
+        return Some(Err(anyhow::anyhow!(
+            "not a TLS handshake record, content type {}",
+            buf[0]
+        )));
+    }
+
+    if buf.len() < RECORD_HEADER_LEN {
+        return None;
+    }
+
+    Some(Ok(
+        RECORD_HEADER_LEN + u16::from_be_bytes([buf[3], buf[4]]) as usize
+    ))
+}
+
+impl ClientHello {
+    /// Parse the ClientHello from the first TLS record in `buf`. The record needs to be complete,
+    /// and the ClientHello must not span multiple records.
+    pub fn parse(buf: &[u8]) -> Result<ClientHello, Error> {
+        let record_len = record_len(buf).context("truncated record header")??;
+        anyhow::ensure!(buf.len() >= record_len, "truncated record");
+
+        let mut hello = ClientHello {
+            record_len,
+            ..Default::default()
+        };
+
+        let mut reader = Reader {
+            buf: &buf[..record_len],
+            pos: RECORD_HEADER_LEN,
+        };
+
+        anyhow::ensure!(
+            reader.u8()? == HANDSHAKE_TYPE_CLIENT_HELLO,
+            "not a ClientHello"
+        );
+        let handshake_len = reader.u24()?;
+        anyhow::ensure!(
+            reader.remaining() >= handshake_len,
+            "ClientHello spans multiple records, which is not supported"
+        );
+
+        hello.version = reader.u16()?;
+        reader.skip(32)?; // random
+        let session_id_len = reader.u8()? as usize;
+        reader.skip(session_id_len)?;
+
+        let mut cipher_suites = reader.sub_u16()?;
+        while cipher_suites.remaining() > 0 {
+            hello.cipher_suites.push(cipher_suites.u16()?);
+        }
+
+        let compression_len = reader.u8()? as usize;
+        reader.skip(compression_len)?;
+
+        if reader.remaining() == 0 {
+            // no extensions at all
+            return Ok(hello);
+        }
+
+        let mut extensions = reader.sub_u16()?;
+        while extensions.remaining() > 0 {
+            let start = extensions.pos;
+            let ty = extensions.u16()?;
+            let mut data = extensions.sub_u16()?;
+            hello.extensions.push(ty);
+
+            match ty {
+                EXT_SERVER_NAME => {
+                    hello.server_name_extension = Some(start..extensions.pos);
+                    let mut names = data.sub_u16()?;
+                    while names.remaining() > 0 {
+                        let name_type = names.u8()?;
+                        let name_len = names.u16()? as usize;
+                        let name_start = names.pos;
+                        names.skip(name_len)?;
+                        // 0 is host_name, the only type defined
+                        if name_type == 0 && hello.server_name.is_none() {
+                            hello.server_name = Some(name_start..names.pos);
+                        }
+                    }
+                }
+                EXT_SUPPORTED_GROUPS => {
+                    let mut groups = data.sub_u16()?;
+                    while groups.remaining() > 0 {
+                        hello.supported_groups.push(groups.u16()?);
+                    }
+                }
+                EXT_EC_POINT_FORMATS => {
+                    let mut formats = data.sub_u8()?;
+                    while formats.remaining() > 0 {
+                        hello.point_formats.push(formats.u8()?);
+                    }
+                }
+                EXT_SIGNATURE_ALGORITHMS => {
+                    let mut algorithms = data.sub_u16()?;
+                    while algorithms.remaining() > 0 {
+                        hello.signature_algorithms.push(algorithms.u16()?);
+                    }
+                }
+                EXT_ALPN => {
+                    let mut protocols = data.sub_u16()?;
+                    while protocols.remaining() > 0 {
+                        let protocol = protocols.sub_u8()?;
+                        hello.alpn.push(protocol.rest().to_vec());
+                    }
+                }
+                EXT_SUPPORTED_VERSIONS => {
+                    let mut versions = data.sub_u8()?;
+                    while versions.remaining() > 0 {
+                        hello.supported_versions.push(versions.u16()?);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(hello)
+    }
+
+    /// The SNI hostname, if any.
+    pub fn sni<'a>(&self, buf: &'a [u8]) -> Option<&'a str> {
+        let range = self.server_name.clone()?;
+        std::str::from_utf8(&buf[range]).ok()
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        anyhow::ensure!(self.remaining() >= len, "truncated ClientHello");
+        let rv = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(rv)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), Error> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Result<usize, Error> {
+        let bytes = self.take(3)?;
+        Ok(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize)
+    }
+
+    /// A reader over the next `len` bytes. Positions stay relative to the original buffer.
+    fn sub(&mut self, len: usize) -> Result<Reader<'a>, Error> {
+        let start = self.pos;
+        self.skip(len)?;
+        Ok(Reader {
+            buf: &self.buf[..self.pos],
+            pos: start,
+        })
+    }
+
+    /// A reader over a vector prefixed with a one-byte length.
+    fn sub_u8(&mut self) -> Result<Reader<'a>, Error> {
+        let len = self.u8()? as usize;
+        self.sub(len)
+    }
+
+    /// A reader over a vector prefixed with a two-byte length.
+    fn sub_u16(&mut self) -> Result<Reader<'a>, Error> {
+        let len = self.u16()? as usize;
+        self.sub(len)
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.buf[self.pos..]
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Build a minimal ClientHello record with the given SNI and ALPN values.
+    pub fn build_client_hello(sni: Option<&str>, alpn: &[&str]) -> Vec<u8> {
+        let mut extensions = Vec::new();
+
+        // GREASE extension, should be ignored by fingerprints
+        extensions.extend_from_slice(&[0x0a, 0x0a, 0x00, 0x00]);
+
+        if let Some(sni) = sni {
+            let name = sni.as_bytes();
+            extensions.extend_from_slice(&EXT_SERVER_NAME.to_be_bytes());
+            extensions.extend_from_slice(&((name.len() + 5) as u16).to_be_bytes());
+            extensions.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+            extensions.push(0);
+            extensions.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(name);
+        }
+
+        extensions.extend_from_slice(&EXT_SUPPORTED_GROUPS.to_be_bytes());
+        extensions.extend_from_slice(&[0x00, 0x06, 0x00, 0x04, 0x00, 0x1d, 0x00, 0x17]);
+        extensions.extend_from_slice(&EXT_EC_POINT_FORMATS.to_be_bytes());
+        extensions.extend_from_slice(&[0x00, 0x02, 0x01, 0x00]);
+        extensions.extend_from_slice(&EXT_SIGNATURE_ALGORITHMS.to_be_bytes());
+        extensions.extend_from_slice(&[0x00, 0x06, 0x00, 0x04, 0x04, 0x03, 0x08, 0x04]);
+
+        if !alpn.is_empty() {
+            let mut protocols = Vec::new();
+            for protocol in alpn {
+                protocols.push(protocol.len() as u8);
+                protocols.extend_from_slice(protocol.as_bytes());
+            }
+            extensions.extend_from_slice(&EXT_ALPN.to_be_bytes());
+            extensions.extend_from_slice(&((protocols.len() + 2) as u16).to_be_bytes());
+            extensions.extend_from_slice(&(protocols.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(&protocols);
+        }
+
+        extensions.extend_from_slice(&EXT_SUPPORTED_VERSIONS.to_be_bytes());
+        extensions.extend_from_slice(&[0x00, 0x05, 0x04, 0x03, 0x04, 0x03, 0x03]);
+
+        let mut hello = Vec::new();
+        hello.extend_from_slice(&[0x03, 0x03]);
+        hello.extend_from_slice(&[0x42; 32]);
+        hello.push(0); // session id
+        hello.extend_from_slice(&[0x00, 0x08, 0x1a, 0x1a, 0x13, 0x01, 0x13, 0x02, 0xc0, 0x2f]);
+        hello.extend_from_slice(&[0x01, 0x00]); // null compression
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut handshake = vec![HANDSHAKE_TYPE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_parse() {
+        let record = build_client_hello(Some("www.example.com"), &["h2", "http/1.1"]);
+        let hello = ClientHello::parse(&record).unwrap();
+
+        assert_eq!(hello.record_len, record.len());
+        assert_eq!(hello.version, 0x0303);
+        assert_eq!(hello.cipher_suites, vec![0x1a1a, 0x1301, 0x1302, 0xc02f]);
+        assert_eq!(
+            hello.extensions,
+            vec![0x0a0a, 0x0000, 0x000a, 0x000b, 0x000d, 0x0010, 0x002b]
+        );
+        assert_eq!(hello.supported_groups, vec![0x001d, 0x0017]);
+        assert_eq!(hello.point_formats, vec![0]);
+        assert_eq!(hello.signature_algorithms, vec![0x0403, 0x0804]);
+        assert_eq!(hello.supported_versions, vec![0x0304, 0x0303]);
+        assert_eq!(hello.alpn, vec![b"h2".to_vec(), b"http/1.1".to_vec()]);
+        assert_eq!(hello.sni(&record), Some("www.example.com"));
+
+        let ext = hello.server_name_extension.unwrap();
+        assert_eq!(&record[ext.start..ext.start + 2], &[0, 0]);
+        assert_eq!(&record[ext.end - 15..ext.end], b"www.example.com");
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        let record = build_client_hello(Some("www.example.com"), &[]);
+        assert!(record_len(&record[..3]).is_none());
+        assert_eq!(record_len(&record[..5]).unwrap().unwrap(), record.len());
+        assert!(ClientHello::parse(&record[..record.len() - 1]).is_err());
+        assert!(record_len(b"GET / HTTP/1.1").unwrap().is_err());
+    }
+
+    #[test]
+    fn test_grease() {
+        assert!(is_grease(0x0a0a));
+        assert!(is_grease(0xfafa));
+        assert!(!is_grease(0x0a1a));
+        assert!(!is_grease(0x1301));
+    }
+}
@@ -42,6 +42,6 @@ async fn process_connection(mut socket: TcpStream, commandline: Vec<String>) {
     let mut stdio_combined = tokio::io::join(stdout, stdin);
 
     let _ = tokio::io::copy_bidirectional(&mut socket, &mut stdio_combined).await;
-    let _ = command.kill();
+    let _ = command.kill().await;
     tracing::debug!("stopping command");
 }
@@ -1,12 +1,22 @@
 use std::ffi::{CStr, CString};
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
 
 use anyhow::{Context, Error};
 use tokio::io::unix::AsyncFd;
+use tokio::sync::Semaphore;
 
+use crate::ech::Ech;
+use crate::pin::{self, Pin};
+use crate::resolver::DEFAULT_HAPPY_EYEBALLS_DELAY_MS;
+use crate::{CurlCommon, CurlHttpVersion, EchCli, IpVersion, PinningCli, ResolverCli, TlsVersion};
+
+pub mod http;
 pub mod tcp;
 pub mod ws;
 
-#[allow(bad_style)]
+#[allow(bad_style, clippy::upper_case_acronyms)]
 mod bindings {
     use libc::{c_char, size_t};
 
@@ -17,43 +27,254 @@ mod bindings {
 
     pub const CURLOPTTYPE_LONG: CURLoption = 0;
     pub const CURLOPTTYPE_OBJECTPOINT: CURLoption = 10_000;
+    pub const CURLOPTTYPE_FUNCTIONPOINT: CURLoption = 20_000;
+    pub const CURLOPTTYPE_OFF_T: CURLoption = 30_000;
+    pub const CURLOPT_WRITEDATA: CURLoption = CURLOPTTYPE_OBJECTPOINT + 1;
     pub const CURLOPT_URL: CURLoption = CURLOPTTYPE_OBJECTPOINT + 2;
+    pub const CURLOPT_PROXY: CURLoption = CURLOPTTYPE_OBJECTPOINT + 4;
+    pub const CURLOPT_WRITEFUNCTION: CURLoption = CURLOPTTYPE_FUNCTIONPOINT + 11;
+    pub const CURLOPT_POSTFIELDS: CURLoption = CURLOPTTYPE_OBJECTPOINT + 15;
+    pub const CURLOPT_HTTPHEADER: CURLoption = CURLOPTTYPE_OBJECTPOINT + 23;
+    pub const CURLOPT_SSLVERSION: CURLoption = CURLOPTTYPE_LONG + 32;
+    pub const CURLOPT_VERBOSE: CURLoption = CURLOPTTYPE_LONG + 41;
+    pub const CURLOPT_NOPROGRESS: CURLoption = CURLOPTTYPE_LONG + 43;
+    pub const CURLOPT_FAILONERROR: CURLoption = CURLOPTTYPE_LONG + 45;
+    pub const CURLOPT_POST: CURLoption = CURLOPTTYPE_LONG + 47;
+    pub const CURLOPT_XFERINFODATA: CURLoption = CURLOPTTYPE_OBJECTPOINT + 57;
+    pub const CURLOPT_HTTPPROXYTUNNEL: CURLoption = CURLOPTTYPE_LONG + 61;
+    pub const CURLOPT_SSL_VERIFYPEER: CURLoption = CURLOPTTYPE_LONG + 64;
+    pub const CURLOPT_CAINFO: CURLoption = CURLOPTTYPE_OBJECTPOINT + 65;
+    pub const CURLOPT_SSL_VERIFYHOST: CURLoption = CURLOPTTYPE_LONG + 81;
+    pub const CURLOPT_SSL_CIPHER_LIST: CURLoption = CURLOPTTYPE_OBJECTPOINT + 83;
+    pub const CURLOPT_HTTP_VERSION: CURLoption = CURLOPTTYPE_LONG + 84;
+    pub const CURLOPT_DEBUGFUNCTION: CURLoption = CURLOPTTYPE_FUNCTIONPOINT + 94;
+    pub const CURLOPT_DEBUGDATA: CURLoption = CURLOPTTYPE_OBJECTPOINT + 95;
+    pub const CURLOPT_NOSIGNAL: CURLoption = CURLOPTTYPE_LONG + 99;
+    pub const CURLOPT_POSTFIELDSIZE_LARGE: CURLoption = CURLOPTTYPE_OFF_T + 120;
+    pub const CURLOPT_IPRESOLVE: CURLoption = CURLOPTTYPE_LONG + 113;
     pub const CURLOPT_CONNECT_ONLY: CURLoption = CURLOPTTYPE_LONG + 141;
+    pub const CURLOPT_CONNECTTIMEOUT_MS: CURLoption = CURLOPTTYPE_LONG + 156;
+    pub const CURLOPT_RESOLVE: CURLoption = CURLOPTTYPE_OBJECTPOINT + 203;
+    pub const CURLOPT_XFERINFOFUNCTION: CURLoption = CURLOPTTYPE_FUNCTIONPOINT + 219;
+    pub const CURLOPT_SSL_ENABLE_ALPN: CURLoption = CURLOPTTYPE_LONG + 226;
+    pub const CURLOPT_HAPPY_EYEBALLS_TIMEOUT_MS: CURLoption = CURLOPTTYPE_LONG + 271;
+    pub const CURLOPT_PINNEDPUBLICKEY: CURLoption = CURLOPTTYPE_OBJECTPOINT + 230;
+    pub const CURLOPT_CONNECT_TO: CURLoption = CURLOPTTYPE_OBJECTPOINT + 243;
+    pub const CURLOPT_TLS13_CIPHERS: CURLoption = CURLOPTTYPE_OBJECTPOINT + 276;
+    pub const CURLOPT_DOH_URL: CURLoption = CURLOPTTYPE_OBJECTPOINT + 279;
+    pub const CURLOPT_SSL_EC_CURVES: CURLoption = CURLOPTTYPE_OBJECTPOINT + 298;
+    pub const CURLOPT_ECH: CURLoption = CURLOPTTYPE_OBJECTPOINT + 325;
+
+    pub const CURL_HTTP_VERSION_1_0: libc::c_long = 1;
+    pub const CURL_HTTP_VERSION_1_1: libc::c_long = 2;
+    pub const CURL_HTTP_VERSION_2_0: libc::c_long = 3;
+    pub const CURL_HTTP_VERSION_2TLS: libc::c_long = 4;
+    pub const CURL_HTTP_VERSION_2_PRIOR_KNOWLEDGE: libc::c_long = 5;
+    pub const CURL_HTTP_VERSION_3: libc::c_long = 30;
+
+    pub const CURL_SSLVERSION_DEFAULT: libc::c_long = 0;
+    pub const CURL_SSLVERSION_TLSv1_0: libc::c_long = 4;
+    pub const CURL_SSLVERSION_TLSv1_1: libc::c_long = 5;
+    pub const CURL_SSLVERSION_TLSv1_2: libc::c_long = 6;
+    pub const CURL_SSLVERSION_TLSv1_3: libc::c_long = 7;
+    // the maximum version is passed in the upper 16 bits of CURLOPT_SSLVERSION
+    pub const CURL_SSLVERSION_MAX_SHIFT: libc::c_long = 16;
+
+    pub const CURL_IPRESOLVE_WHATEVER: libc::c_long = 0;
+    pub const CURL_IPRESOLVE_V4: libc::c_long = 1;
+    pub const CURL_IPRESOLVE_V6: libc::c_long = 2;
+
+    pub const CURL_GLOBAL_DEFAULT: libc::c_long = 3;
+
     pub const CURLE_OK: CURLcode = 0;
+    pub const CURLE_COULDNT_RESOLVE_PROXY: CURLcode = 5;
+    pub const CURLE_GOT_NOTHING: CURLcode = 52;
     pub const CURLE_AGAIN: CURLcode = 81;
+    pub const CURLE_SSL_PINNEDPUBKEYNOTMATCH: CURLcode = 90;
+    pub const CURLE_PROXY: CURLcode = 97;
+    pub const CURLE_ECH_REQUIRED: CURLcode = 101;
+    // CURLproxycode values reported by CURLINFO_PROXY_ERROR
+    pub const CURLPX_NO_AUTH: libc::c_long = 12;
+    pub const CURLPX_USER_REJECTED: libc::c_long = 33;
+
     pub const CURLWS_BINARY: libc::c_uint = 1 << 1;
+    pub const CURLWS_CLOSE: libc::c_uint = 1 << 3;
 
+    pub const CURLINFO_STRING: __enum_ty = 0x100000;
+    pub const CURLINFO_PRIMARY_IP: __enum_ty = CURLINFO_STRING + 32;
+    pub const CURLINFO_LONG: __enum_ty = 0x200000;
+    pub const CURLINFO_HTTP_CONNECTCODE: __enum_ty = CURLINFO_LONG + 22;
+    pub const CURLINFO_PRIMARY_PORT: __enum_ty = CURLINFO_LONG + 40;
+    pub const CURLINFO_HTTP_VERSION: __enum_ty = CURLINFO_LONG + 46;
+    pub const CURLINFO_PROXY_ERROR: __enum_ty = CURLINFO_LONG + 59;
     pub const CURLINFO_SOCKET: __enum_ty = 0x500000;
     pub const CURLINFO_ACTIVESOCKET: __enum_ty = CURLINFO_SOCKET + 44;
+    pub const CURLINFO_PTR: __enum_ty = 0x400000;
+    pub const CURLINFO_TLS_SSL_PTR: __enum_ty = CURLINFO_PTR + 45;
+
+    // curl_infotype, the kind of message passed to CURLOPT_DEBUGFUNCTION
+    pub const CURLINFO_TEXT: libc::c_int = 0;
+    pub const CURLINFO_HEADER_IN: libc::c_int = 1;
+    pub const CURLINFO_HEADER_OUT: libc::c_int = 2;
+    pub const CURLINFO_SSL_DATA_IN: libc::c_int = 5;
+    pub const CURLINFO_SSL_DATA_OUT: libc::c_int = 6;
+
+    // curl_sslbackend, BoringSSL and LibreSSL report themselves as OpenSSL
+    pub const CURLSSLBACKEND_OPENSSL: __enum_ty = 1;
     pub type curl_off_t = i64;
     pub type curl_socket_t = libc::c_int;
 
+    pub type curl_write_callback = extern "C" fn(
+        buffer: *const c_char,
+        size: size_t,
+        nitems: size_t,
+        userdata: *mut libc::c_void,
+    ) -> size_t;
+    pub type curl_debug_callback = extern "C" fn(
+        handle: *mut CURL,
+        kind: libc::c_int,
+        data: *const c_char,
+        size: size_t,
+        userptr: *mut libc::c_void,
+    ) -> libc::c_int;
+    pub type curl_xferinfo_callback = extern "C" fn(
+        clientp: *mut libc::c_void,
+        dltotal: curl_off_t,
+        dlnow: curl_off_t,
+        ultotal: curl_off_t,
+        ulnow: curl_off_t,
+    ) -> libc::c_int;
+
     pub enum CURL {}
+    pub enum curl_slist {}
 
     // CURL client can be sent across threads but not used concurrently
-    pub struct SendableCurl(pub *mut CURL);
+    pub struct SendableCurl {
+        pub handle: *mut CURL,
+        // to tell connections apart in logs
+        pub id: u64,
+        // passed to CURLOPT_DEBUGDATA, dropped only after curl_easy_cleanup
+        pub debug: Option<Box<super::CurlDebugState>>,
+        // the library the handle was created with, all calls need to go through it
+        pub api: &'static CurlApi,
+        // lists that were passed to curl_easy_setopt, they need to stay alive as long as the
+        // handle does.
+        pub slists: Vec<*mut curl_slist>,
+    }
     unsafe impl Send for SendableCurl {}
 
+    impl Drop for SendableCurl {
+        fn drop(&mut self) {
+            unsafe {
+                // also closes the connection
+                (self.api.curl_easy_cleanup)(self.handle);
+                for list in self.slists.drain(..) {
+                    (self.api.curl_slist_free_all)(list);
+                }
+            }
+        }
+    }
+
+    /// Function table of a libcurl build, either the one minidialer is linked against, or one
+    /// that was loaded at runtime with dlopen.
+    pub struct CurlApi {
+        pub curl_global_init: unsafe extern "C" fn(flags: libc::c_long) -> CURLcode,
+        pub curl_easy_init: unsafe extern "C" fn() -> *mut CURL,
+        pub curl_easy_cleanup: unsafe extern "C" fn(curl: *mut CURL),
+        pub curl_easy_reset: unsafe extern "C" fn(curl: *mut CURL),
+        pub curl_easy_setopt:
+            unsafe extern "C" fn(curl: *mut CURL, option: CURLoption, ...) -> CURLcode,
+        pub curl_easy_perform: unsafe extern "C" fn(curl: *mut CURL) -> CURLcode,
+        pub curl_slist_append:
+            unsafe extern "C" fn(list: *mut curl_slist, string: *const c_char) -> *mut curl_slist,
+        pub curl_slist_free_all: unsafe extern "C" fn(list: *mut curl_slist),
+        pub curl_easy_send: unsafe extern "C" fn(
+            curl: *mut CURL,
+            buffer: *const u8,
+            buflen: size_t,
+            n: *mut size_t,
+        ) -> CURLcode,
+        pub curl_easy_recv: unsafe extern "C" fn(
+            curl: *mut CURL,
+            buffer: *const u8,
+            buflen: size_t,
+            n: *mut size_t,
+        ) -> CURLcode,
+        pub curl_ws_send: unsafe extern "C" fn(
+            curl: *mut CURL,
+            buffer: *const u8,
+            buflen: size_t,
+            sent: *mut size_t,
+            fragsize: curl_off_t,
+            flags: libc::c_uint,
+        ) -> CURLcode,
+        pub curl_ws_recv: unsafe extern "C" fn(
+            curl: *mut CURL,
+            buffer: *const u8,
+            buflen: size_t,
+            recv: *mut size_t,
+            meta: *mut *mut curl_ws_frame,
+        ) -> CURLcode,
+        pub curl_easy_getinfo:
+            unsafe extern "C" fn(handle: *mut CURL, info: __enum_ty, ...) -> CURLcode,
+        // only exported by curl-impersonate
+        pub curl_easy_impersonate: Option<
+            unsafe extern "C" fn(
+                curl: *mut CURL,
+                target: *const c_char,
+                default_headers: libc::c_int,
+            ) -> CURLcode,
+        >,
+        // only available if curl is built against OpenSSL or BoringSSL, used to log the
+        // negotiated TLS parameters
+        pub openssl: Option<OpensslApi>,
+    }
+
+    /// The parts of OpenSSL needed to inspect the SSL* that CURLINFO_TLS_SSL_PTR returns.
+    pub struct OpensslApi {
+        pub SSL_get_version: unsafe extern "C" fn(ssl: *const libc::c_void) -> *const c_char,
+        pub SSL_get_current_cipher:
+            unsafe extern "C" fn(ssl: *const libc::c_void) -> *const libc::c_void,
+        pub SSL_CIPHER_get_name: unsafe extern "C" fn(cipher: *const libc::c_void) -> *const c_char,
+        pub SSL_get0_alpn_selected: unsafe extern "C" fn(
+            ssl: *const libc::c_void,
+            data: *mut *const u8,
+            len: *mut libc::c_uint,
+        ),
+    }
+
+    #[repr(C)]
+    pub struct curl_tlssessioninfo {
+        pub backend: __enum_ty,
+        pub internals: *mut libc::c_void,
+    }
+
     #[repr(C)]
     pub struct curl_ws_frame {
-        age: libc::c_int,      /* zero */
-        flags: libc::c_int,    /* See the CURLWS_* defines */
-        offset: curl_off_t,    /* the offset of this data into the frame */
-        bytesleft: curl_off_t, /* number of pending bytes left of the payload */
-        len: size_t,           /* size of the current data chunk */
+        age: libc::c_int,       /* zero */
+        pub flags: libc::c_int, /* See the CURLWS_* defines */
+        offset: curl_off_t,     /* the offset of this data into the frame */
+        bytesleft: curl_off_t,  /* number of pending bytes left of the payload */
+        len: size_t,            /* size of the current data chunk */
     }
 
     // copypasted from curl-sys' lib.rs and partially hand-written, because curl-sys does not have
     // symbols for websocket (curl_ws_..)
     #[link(name = "curl")]
     extern "C" {
+        pub fn curl_global_init(flags: libc::c_long) -> CURLcode;
         pub fn curl_easy_init() -> *mut CURL;
+        pub fn curl_easy_cleanup(curl: *mut CURL);
+        pub fn curl_easy_reset(curl: *mut CURL);
         #[must_use]
         pub fn curl_easy_setopt(curl: *mut CURL, option: CURLoption, ...) -> CURLcode;
         #[must_use]
         pub fn curl_easy_perform(curl: *mut CURL) -> CURLcode;
         pub fn curl_easy_strerror(code: CURLcode) -> *const c_char;
 
+        pub fn curl_slist_append(list: *mut curl_slist, string: *const c_char) -> *mut curl_slist;
+        pub fn curl_slist_free_all(list: *mut curl_slist);
+
         #[must_use]
         pub fn curl_easy_send(
             curl: *mut CURL,
@@ -90,11 +311,7 @@ mod bindings {
         ) -> CURLcode;
 
         #[must_use]
-        pub fn curl_easy_getinfo(
-            handle: *mut CURL,
-            info: __enum_ty,
-            socket: *mut curl_socket_t,
-        ) -> CURLcode;
+        pub fn curl_easy_getinfo(handle: *mut CURL, info: __enum_ty, ...) -> CURLcode;
     }
 }
 
@@ -107,38 +324,798 @@ fn check_err(code: bindings::CURLcode) -> Result<(), Error> {
     Ok(())
 }
 
-fn curl_connect_only(url: &str, value: usize) -> Result<bindings::SendableCurl, Error> {
-    let curl_client = unsafe {
-        let rv = bindings::curl_easy_init();
-        assert!(!rv.is_null());
-        let url = CString::new(url).unwrap();
-        check_err(bindings::curl_easy_setopt(
-            rv,
-            bindings::CURLOPT_URL,
-            url.as_ptr(),
-        ))
-        .unwrap();
-        check_err(bindings::curl_easy_setopt(
-            rv,
-            bindings::CURLOPT_CONNECT_ONLY,
-            value as libc::c_longlong,
-        ))
-        .unwrap();
-        bindings::SendableCurl(rv)
+/// The libcurl minidialer is linked against. curl_easy_impersonate is looked up dynamically, so
+/// it is available if curl-impersonate is injected with LD_PRELOAD.
+fn curl_linked_api() -> &'static bindings::CurlApi {
+    static API: OnceLock<bindings::CurlApi> = OnceLock::new();
+    API.get_or_init(|| {
+        let api = bindings::CurlApi {
+            curl_global_init: bindings::curl_global_init,
+            curl_easy_init: bindings::curl_easy_init,
+            curl_easy_cleanup: bindings::curl_easy_cleanup,
+            curl_easy_reset: bindings::curl_easy_reset,
+            curl_easy_setopt: bindings::curl_easy_setopt,
+            curl_easy_perform: bindings::curl_easy_perform,
+            curl_slist_append: bindings::curl_slist_append,
+            curl_slist_free_all: bindings::curl_slist_free_all,
+            curl_easy_send: bindings::curl_easy_send,
+            curl_easy_recv: bindings::curl_easy_recv,
+            curl_ws_send: bindings::curl_ws_send,
+            curl_ws_recv: bindings::curl_ws_recv,
+            curl_easy_getinfo: bindings::curl_easy_getinfo,
+            curl_easy_impersonate: unsafe {
+                curl_dlsym(libc::RTLD_DEFAULT, "curl_easy_impersonate")
+            },
+            openssl: unsafe { openssl_dlsym(libc::RTLD_DEFAULT) },
+        };
+        check_err(unsafe { (api.curl_global_init)(bindings::CURL_GLOBAL_DEFAULT) })
+            .context("curl_global_init failed")
+            .unwrap();
+        api
+    })
+}
+
+/// Load a libcurl build (for example libcurl-impersonate-chrome.so) with dlopen. The library is
+/// never unloaded.
+fn curl_load_api(path: &str) -> Result<&'static bindings::CurlApi, Error> {
+    let c_path = CString::new(path).context("invalid library path")?;
+    let lib = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    if lib.is_null() {
+        let err = unsafe { CStr::from_ptr(libc::dlerror()) };
+        anyhow::bail!("failed to load {}: {:?}", path, err);
+    }
+
+    macro_rules! required {
+        ($name:ident) => {
+            unsafe { curl_dlsym(lib, stringify!($name)) }
+                .with_context(|| format!("{} not found in {}", stringify!($name), path))?
+        };
+    }
+
+    let api = bindings::CurlApi {
+        curl_global_init: required!(curl_global_init),
+        curl_easy_init: required!(curl_easy_init),
+        curl_easy_cleanup: required!(curl_easy_cleanup),
+        curl_easy_reset: required!(curl_easy_reset),
+        curl_easy_setopt: required!(curl_easy_setopt),
+        curl_easy_perform: required!(curl_easy_perform),
+        curl_slist_append: required!(curl_slist_append),
+        curl_slist_free_all: required!(curl_slist_free_all),
+        curl_easy_send: required!(curl_easy_send),
+        curl_easy_recv: required!(curl_easy_recv),
+        curl_ws_send: required!(curl_ws_send),
+        curl_ws_recv: required!(curl_ws_recv),
+        curl_easy_getinfo: required!(curl_easy_getinfo),
+        curl_easy_impersonate: unsafe { curl_dlsym(lib, "curl_easy_impersonate") },
+        openssl: unsafe { openssl_dlsym(lib) },
     };
 
-    check_err(unsafe { bindings::curl_easy_perform(curl_client.0) })?;
+    check_err(unsafe { (api.curl_global_init)(bindings::CURL_GLOBAL_DEFAULT) })
+        .context("curl_global_init failed")?;
+
+    Ok(Box::leak(Box::new(api)))
+}
+
+/// Look up the OpenSSL functions in a library or its dependencies. curl-impersonate has
+/// BoringSSL linked in statically.
+unsafe fn openssl_dlsym(lib: *mut libc::c_void) -> Option<bindings::OpensslApi> {
+    Some(bindings::OpensslApi {
+        SSL_get_version: curl_dlsym(lib, "SSL_get_version")?,
+        SSL_get_current_cipher: curl_dlsym(lib, "SSL_get_current_cipher")?,
+        SSL_CIPHER_get_name: curl_dlsym(lib, "SSL_CIPHER_get_name")?,
+        SSL_get0_alpn_selected: curl_dlsym(lib, "SSL_get0_alpn_selected")?,
+    })
+}
+
+/// Look up a function in a library. `F` has to be the function pointer type matching the symbol.
+unsafe fn curl_dlsym<F>(lib: *mut libc::c_void, name: &str) -> Option<F> {
+    assert_eq!(
+        std::mem::size_of::<F>(),
+        std::mem::size_of::<*mut libc::c_void>()
+    );
+    let c_name = CString::new(name).unwrap();
+    let sym = libc::dlsym(lib, c_name.as_ptr());
+    if sym.is_null() {
+        None
+    } else {
+        Some(std::mem::transmute_copy(&sym))
+    }
+}
+
+/// Runs curl handshakes on tokio's blocking thread pool.
+///
+/// With CONNECT_ONLY, curl_easy_perform does DNS lookup, TCP connect and TLS handshake
+/// synchronously, which would otherwise stall a tokio worker thread for every new connection.
+#[derive(Clone)]
+struct CurlConnector {
+    api: &'static bindings::CurlApi,
+    args: Arc<CurlCommon>,
+    resolver: Arc<ResolverCli>,
+    pins: Arc<Vec<Pin>>,
+    // values for CURLOPT_ECH, empty without --ech
+    ech: Arc<Vec<String>>,
+    handshakes: Arc<Semaphore>,
+}
+
+impl CurlConnector {
+    fn new(
+        args: &CurlCommon,
+        resolver: &ResolverCli,
+        pinning: &PinningCli,
+        ech: &EchCli,
+    ) -> Result<Self, Error> {
+        let pins = pinning
+            .pin
+            .iter()
+            .map(|pin| Pin::parse(pin))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let api = match args.libcurl {
+            Some(ref path) => {
+                tracing::info!("loading libcurl from {}", path);
+                curl_load_api(path)?
+            }
+            None => curl_linked_api(),
+        };
+
+        if args.impersonate.is_some() && api.curl_easy_impersonate.is_none() {
+            anyhow::bail!(
+                "--impersonate requires curl-impersonate, but the loaded libcurl does not export curl_easy_impersonate. use --libcurl to load libcurl-impersonate"
+            );
+        }
+
+        let ech = Ech::from_args(ech)?;
+        if let Some(ref ech) = ech {
+            if !curl_supports_ech(api) {
+                anyhow::bail!(
+                    "--ech requires curl 8.8 or newer built with ECH support, which the loaded libcurl is not. use --libcurl to load another build"
+                );
+            }
+            if *ech == Ech::Dns && resolver.doh_url.is_none() {
+                anyhow::bail!(
+                    "curl can only look up the ECH config with --doh-url, or use --ech-config"
+                );
+            }
+        }
+
+        Ok(CurlConnector {
+            api,
+            args: Arc::new(args.clone()),
+            resolver: Arc::new(resolver.clone()),
+            pins: Arc::new(pins),
+            ech: Arc::new(ech.map(|ech| ech.curl_options()).unwrap_or_default()),
+            handshakes: Arc::new(Semaphore::new(args.max_concurrent_handshakes)),
+        })
+    }
+
+    async fn connect_only(
+        &self,
+        url: String,
+        value: usize,
+        headers: Vec<String>,
+    ) -> Result<bindings::SendableCurl, Error> {
+        let _permit = self.handshakes.acquire().await?;
+        let connector = self.clone();
+        tokio::task::spawn_blocking(move || curl_connect_only(&connector, &url, value, &headers))
+            .await?
+    }
+}
+
+fn curl_connect_only(
+    connector: &CurlConnector,
+    url: &str,
+    value: usize,
+    headers: &[String],
+) -> Result<bindings::SendableCurl, Error> {
+    let curl_client = curl_easy_new(connector, url, headers)?;
+
+    curl_setopt_long(
+        &curl_client,
+        bindings::CURLOPT_CONNECT_ONLY,
+        "CURLOPT_CONNECT_ONLY",
+        value as libc::c_long,
+    )?;
 
+    curl_perform(&curl_client)?;
+    curl_log_connection(&curl_client);
+
+    Ok(curl_client)
+}
+
+/// Create a curl handle for the given URL, with all options from the commandline applied.
+fn curl_easy_new(
+    connector: &CurlConnector,
+    url: &str,
+    headers: &[String],
+) -> Result<bindings::SendableCurl, Error> {
+    let api = connector.api;
+    let handle = unsafe { (api.curl_easy_init)() };
+    assert!(!handle.is_null());
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    let mut curl_client = bindings::SendableCurl {
+        handle,
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        debug: None,
+        api,
+        slists: Vec::new(),
+    };
+
+    curl_easy_setup(connector, &mut curl_client, url, headers)?;
     Ok(curl_client)
 }
 
+/// Reset all options of a handle, so that it can be set up for another request. Its connection
+/// stays open and is reused if the next request goes to the same upstream.
+fn curl_easy_reset(curl_client: &mut bindings::SendableCurl) {
+    unsafe {
+        (curl_client.api.curl_easy_reset)(curl_client.handle);
+        for list in curl_client.slists.drain(..) {
+            (curl_client.api.curl_slist_free_all)(list);
+        }
+    }
+}
+
+/// Apply all options from the commandline to a new or reset handle.
+fn curl_easy_setup(
+    connector: &CurlConnector,
+    curl_client: &mut bindings::SendableCurl,
+    url: &str,
+    headers: &[String],
+) -> Result<(), Error> {
+    let api = connector.api;
+    let args = &*connector.args;
+    let handle = curl_client.handle;
+
+    if let Some(ref target) = args.impersonate {
+        // must come first, as it overrides other TLS options
+        let impersonate = api.curl_easy_impersonate.unwrap();
+        let target = CString::new(target.as_str()).context("invalid impersonate target")?;
+        check_err(unsafe { impersonate(handle, target.as_ptr(), 1) })
+            .context("curl_easy_impersonate failed")?;
+    }
+
+    // the negotiated TLS parameters can only be read while curl_easy_perform runs, so the debug
+    // callback is also needed to log them
+    if args.curl_verbose || tracing::enabled!(tracing::Level::DEBUG) {
+        let id = curl_client.id;
+        let debug = curl_client.debug.get_or_insert_with(|| {
+            Box::new(CurlDebugState {
+                id,
+                api,
+                verbose: args.curl_verbose,
+                tls: OnceLock::new(),
+            })
+        });
+        unsafe {
+            check_err((api.curl_easy_setopt)(
+                handle,
+                bindings::CURLOPT_DEBUGFUNCTION,
+                curl_debug as bindings::curl_debug_callback,
+            ))
+            .context("curl_easy_setopt(CURLOPT_DEBUGFUNCTION) failed")?;
+            check_err((api.curl_easy_setopt)(
+                handle,
+                bindings::CURLOPT_DEBUGDATA,
+                &**debug as *const CurlDebugState,
+            ))
+            .context("curl_easy_setopt(CURLOPT_DEBUGDATA) failed")?;
+        }
+        curl_setopt_long(curl_client, bindings::CURLOPT_VERBOSE, "CURLOPT_VERBOSE", 1)?;
+    }
+
+    tracing::debug!("[curl {}] connecting to {}", curl_client.id, url);
+    curl_setopt_str(curl_client, bindings::CURLOPT_URL, "CURLOPT_URL", url)?;
+    // handles are used from many threads, signals can not be used for timeouts
+    curl_setopt_long(
+        curl_client,
+        bindings::CURLOPT_NOSIGNAL,
+        "CURLOPT_NOSIGNAL",
+        1,
+    )?;
+    curl_setopt_slist(
+        curl_client,
+        bindings::CURLOPT_HTTPHEADER,
+        "CURLOPT_HTTPHEADER",
+        headers,
+    )?;
+    curl_set_options(curl_client, args)?;
+    curl_set_resolver_options(curl_client, &connector.resolver)?;
+
+    let host = reqwest::Url::parse(url).ok().and_then(|url| {
+        url.host_str()
+            .map(|host| host.trim_matches(['[', ']']).to_owned())
+    });
+    if let Some(pins) = host.and_then(|host| pin::curl_pinned_public_key(&connector.pins, &host)) {
+        curl_setopt_str(
+            curl_client,
+            bindings::CURLOPT_PINNEDPUBLICKEY,
+            "CURLOPT_PINNEDPUBLICKEY",
+            &pins,
+        )?;
+    }
+
+    for ech in connector.ech.iter() {
+        curl_setopt_str(curl_client, bindings::CURLOPT_ECH, "CURLOPT_ECH", ech)?;
+    }
+
+    Ok(())
+}
+
+/// Whether the libcurl knows CURLOPT_ECH, which only exists in builds with ECH support.
+fn curl_supports_ech(api: &bindings::CurlApi) -> bool {
+    let handle = unsafe { (api.curl_easy_init)() };
+    assert!(!handle.is_null());
+    let value = CString::new("false").unwrap();
+    let code = unsafe { (api.curl_easy_setopt)(handle, bindings::CURLOPT_ECH, value.as_ptr()) };
+    unsafe { (api.curl_easy_cleanup)(handle) };
+    code == bindings::CURLE_OK
+}
+
+/// State of curl_debug for one handle.
+struct CurlDebugState {
+    id: u64,
+    api: &'static bindings::CurlApi,
+    // whether to forward curl's verbose output, from --curl-verbose
+    verbose: bool,
+    // TLS version, cipher and ALPN, captured when the handshake completes
+    tls: OnceLock<String>,
+}
+
+/// Receives curl's verbose output, forwards it into tracing if --curl-verbose is set, and
+/// captures the TLS parameters once the handshake is done.
+extern "C" fn curl_debug(
+    handle: *mut bindings::CURL,
+    kind: libc::c_int,
+    data: *const libc::c_char,
+    size: libc::size_t,
+    userptr: *mut libc::c_void,
+) -> libc::c_int {
+    let state = unsafe { &*(userptr as *const CurlDebugState) };
+    let data = unsafe { std::slice::from_raw_parts(data as *const u8, size) };
+    let id = state.id;
+
+    match kind {
+        bindings::CURLINFO_TEXT => {
+            let text = String::from_utf8_lossy(data);
+            // CURLINFO_TLS_SSL_PTR is only available while the connection is attached to the
+            // handle, which is not the case anymore after a CONNECT_ONLY curl_easy_perform.
+            if text.starts_with("SSL connection using") || text.starts_with("ALPN: server") {
+                if let Some(tls) = curl_tls_info(state.api, handle) {
+                    let _ = state.tls.set(tls);
+                }
+            }
+
+            if state.verbose {
+                tracing::debug!("[curl {}] * {}", id, text.trim_end());
+            }
+        }
+        _ if !state.verbose => {}
+        bindings::CURLINFO_HEADER_IN | bindings::CURLINFO_HEADER_OUT => {
+            let direction = if kind == bindings::CURLINFO_HEADER_IN {
+                '<'
+            } else {
+                '>'
+            };
+            for line in String::from_utf8_lossy(data).lines() {
+                if !line.is_empty() {
+                    tracing::debug!("[curl {}] {} {}", id, direction, line);
+                }
+            }
+        }
+        bindings::CURLINFO_SSL_DATA_IN | bindings::CURLINFO_SSL_DATA_OUT => {
+            let direction = if kind == bindings::CURLINFO_SSL_DATA_IN {
+                "received"
+            } else {
+                "sent"
+            };
+            tracing::trace!("[curl {}] TLS {} {} bytes", id, direction, size);
+        }
+        // payload, too noisy
+        _ => {}
+    }
+
+    0
+}
+
+/// Log where a tunnel ended up and which TLS parameters were negotiated.
+fn curl_log_connection(curl_client: &bindings::SendableCurl) {
+    if !tracing::enabled!(tracing::Level::DEBUG) {
+        return;
+    }
+
+    let mut ip: *const libc::c_char = std::ptr::null();
+    let ip = unsafe {
+        let res = (curl_client.api.curl_easy_getinfo)(
+            curl_client.handle,
+            bindings::CURLINFO_PRIMARY_IP,
+            (&mut ip) as *mut *const libc::c_char,
+        );
+        if check_err(res).is_err() || ip.is_null() {
+            "unknown".into()
+        } else {
+            CStr::from_ptr(ip).to_string_lossy()
+        }
+    };
+    let port = curl_getinfo_long(curl_client, bindings::CURLINFO_PRIMARY_PORT);
+    let http_version = match curl_getinfo_long(curl_client, bindings::CURLINFO_HTTP_VERSION) {
+        bindings::CURL_HTTP_VERSION_1_0 => "HTTP/1.0",
+        bindings::CURL_HTTP_VERSION_1_1 => "HTTP/1.1",
+        bindings::CURL_HTTP_VERSION_2_0 => "HTTP/2",
+        bindings::CURL_HTTP_VERSION_3 => "HTTP/3",
+        _ => "no HTTP request",
+    };
+
+    let tls = curl_client
+        .debug
+        .as_ref()
+        .and_then(|debug| debug.tls.get())
+        .map(String::as_str)
+        .unwrap_or("no TLS details");
+
+    tracing::debug!(
+        "[curl {}] connected to {}:{}, {}, {}",
+        curl_client.id,
+        ip,
+        port,
+        tls,
+        http_version
+    );
+}
+
+/// Describe the TLS version, cipher and ALPN of a connection, if curl uses OpenSSL.
+fn curl_tls_info(api: &bindings::CurlApi, handle: *mut bindings::CURL) -> Option<String> {
+    let openssl = api.openssl.as_ref()?;
+
+    let mut info: *const bindings::curl_tlssessioninfo = std::ptr::null();
+    unsafe {
+        let res = (api.curl_easy_getinfo)(
+            handle,
+            bindings::CURLINFO_TLS_SSL_PTR,
+            (&mut info) as *mut *const bindings::curl_tlssessioninfo,
+        );
+        if check_err(res).is_err()
+            || info.is_null()
+            || (*info).backend != bindings::CURLSSLBACKEND_OPENSSL
+            || (*info).internals.is_null()
+        {
+            return None;
+        }
+
+        let ssl = (*info).internals as *const libc::c_void;
+        let version = CStr::from_ptr((openssl.SSL_get_version)(ssl)).to_string_lossy();
+
+        let cipher = (openssl.SSL_get_current_cipher)(ssl);
+        let cipher = if cipher.is_null() {
+            "unknown".into()
+        } else {
+            CStr::from_ptr((openssl.SSL_CIPHER_get_name)(cipher)).to_string_lossy()
+        };
+
+        let mut alpn: *const u8 = std::ptr::null();
+        let mut alpn_len: libc::c_uint = 0;
+        (openssl.SSL_get0_alpn_selected)(ssl, &mut alpn, &mut alpn_len);
+        let alpn = if alpn.is_null() || alpn_len == 0 {
+            "none".into()
+        } else {
+            String::from_utf8_lossy(std::slice::from_raw_parts(alpn, alpn_len as usize))
+        };
+
+        Some(format!("{} {}, ALPN {}", version, cipher, alpn))
+    }
+}
+
+/// Run curl_easy_perform. If it fails because of the upstream proxy, the error says so instead
+/// of only showing curl's generic error code.
+fn curl_perform(curl_client: &bindings::SendableCurl) -> Result<(), Error> {
+    let code = unsafe { (curl_client.api.curl_easy_perform)(curl_client.handle) };
+    let err = match check_err(code) {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+
+    let connect_code = curl_getinfo_long(curl_client, bindings::CURLINFO_HTTP_CONNECTCODE);
+    let proxy_error = if code == bindings::CURLE_PROXY {
+        curl_getinfo_long(curl_client, bindings::CURLINFO_PROXY_ERROR)
+    } else {
+        0
+    };
+
+    if connect_code == 407
+        || proxy_error == bindings::CURLPX_USER_REJECTED
+        || proxy_error == bindings::CURLPX_NO_AUTH
+    {
+        Err(err.context("upstream proxy rejected the credentials"))
+    } else if connect_code != 0 && !(200..300).contains(&connect_code) {
+        Err(err.context(format!(
+            "upstream proxy refused CONNECT with status {}",
+            connect_code
+        )))
+    } else if code == bindings::CURLE_PROXY {
+        Err(err.context(format!(
+            "upstream proxy handshake failed (CURLproxycode {})",
+            proxy_error
+        )))
+    } else if code == bindings::CURLE_SSL_PINNEDPUBKEYNOTMATCH {
+        Err(err.context("public key of the upstream does not match any --pin, refusing to connect"))
+    } else if code == bindings::CURLE_ECH_REQUIRED {
+        Err(err.context("upstream did not accept ECH, refusing to connect without it"))
+    } else if code == bindings::CURLE_COULDNT_RESOLVE_PROXY {
+        Err(err.context("failed to resolve upstream proxy"))
+    } else {
+        Err(err)
+    }
+}
+
+/// Read a long from curl_easy_getinfo, or 0 if the info is not available in this libcurl.
+fn curl_getinfo_long(
+    curl_client: &bindings::SendableCurl,
+    info: bindings::__enum_ty,
+) -> libc::c_long {
+    let mut value: libc::c_long = 0;
+    let res = unsafe {
+        (curl_client.api.curl_easy_getinfo)(
+            curl_client.handle,
+            info,
+            (&mut value) as *mut libc::c_long,
+        )
+    };
+    if check_err(res).is_err() {
+        return 0;
+    }
+    value
+}
+
+/// Apply the TLS and HTTP options from the commandline to a fresh curl handle.
+fn curl_set_options(
+    curl_client: &mut bindings::SendableCurl,
+    args: &CurlCommon,
+) -> Result<(), Error> {
+    if let Some(ref ciphers) = args.ciphers {
+        curl_setopt_str(
+            curl_client,
+            bindings::CURLOPT_SSL_CIPHER_LIST,
+            "CURLOPT_SSL_CIPHER_LIST",
+            ciphers,
+        )?;
+    }
+
+    if let Some(ref ciphers) = args.tls13_ciphers {
+        curl_setopt_str(
+            curl_client,
+            bindings::CURLOPT_TLS13_CIPHERS,
+            "CURLOPT_TLS13_CIPHERS",
+            ciphers,
+        )?;
+    }
+
+    if let Some(ref curves) = args.curves {
+        curl_setopt_str(
+            curl_client,
+            bindings::CURLOPT_SSL_EC_CURVES,
+            "CURLOPT_SSL_EC_CURVES",
+            curves,
+        )?;
+    }
+
+    if args.tls_min.is_some() || args.tls_max.is_some() {
+        let min = args
+            .tls_min
+            .map(TlsVersion::to_curl)
+            .unwrap_or(bindings::CURL_SSLVERSION_DEFAULT);
+        let max = args
+            .tls_max
+            .map(|v| v.to_curl() << bindings::CURL_SSLVERSION_MAX_SHIFT)
+            .unwrap_or(0);
+        curl_setopt_long(
+            curl_client,
+            bindings::CURLOPT_SSLVERSION,
+            "CURLOPT_SSLVERSION",
+            min | max,
+        )?;
+    }
+
+    if args.no_alpn {
+        curl_setopt_long(
+            curl_client,
+            bindings::CURLOPT_SSL_ENABLE_ALPN,
+            "CURLOPT_SSL_ENABLE_ALPN",
+            0,
+        )?;
+    }
+
+    if let Some(http_version) = args.http_version {
+        curl_setopt_long(
+            curl_client,
+            bindings::CURLOPT_HTTP_VERSION,
+            "CURLOPT_HTTP_VERSION",
+            http_version.to_curl(),
+        )?;
+    }
+
+    if let Some(ref proxy) = args.upstream_proxy {
+        curl_setopt_str(curl_client, bindings::CURLOPT_PROXY, "CURLOPT_PROXY", proxy)?;
+        // without this, HTTP proxies see plain http:// and ws:// requests, and CONNECT_ONLY
+        // would talk to the proxy instead of the upstream
+        curl_setopt_long(
+            curl_client,
+            bindings::CURLOPT_HTTPPROXYTUNNEL,
+            "CURLOPT_HTTPPROXYTUNNEL",
+            1,
+        )?;
+    }
+
+    if let Some(ref cacert) = args.cacert {
+        curl_setopt_str(
+            curl_client,
+            bindings::CURLOPT_CAINFO,
+            "CURLOPT_CAINFO",
+            cacert,
+        )?;
+    }
+
+    if args.insecure {
+        curl_setopt_long(
+            curl_client,
+            bindings::CURLOPT_SSL_VERIFYPEER,
+            "CURLOPT_SSL_VERIFYPEER",
+            0,
+        )?;
+        curl_setopt_long(
+            curl_client,
+            bindings::CURLOPT_SSL_VERIFYHOST,
+            "CURLOPT_SSL_VERIFYHOST",
+            0,
+        )?;
+    }
+
+    curl_setopt_slist(
+        curl_client,
+        bindings::CURLOPT_CONNECT_TO,
+        "CURLOPT_CONNECT_TO",
+        &args.connect_to,
+    )?;
+
+    Ok(())
+}
+
+/// Apply --doh-url, --resolve, --ip-version, --happy-eyeballs-delay-ms and --connect-timeout-ms,
+/// which the other modes implement in crate::resolver.
+fn curl_set_resolver_options(
+    curl_client: &mut bindings::SendableCurl,
+    args: &ResolverCli,
+) -> Result<(), Error> {
+    if let Some(ref doh_url) = args.doh_url {
+        curl_setopt_str(
+            curl_client,
+            bindings::CURLOPT_DOH_URL,
+            "CURLOPT_DOH_URL",
+            doh_url,
+        )?;
+    }
+
+    curl_setopt_slist(
+        curl_client,
+        bindings::CURLOPT_RESOLVE,
+        "CURLOPT_RESOLVE",
+        &args.resolve,
+    )?;
+
+    let ip_resolve = match args.ip_version {
+        IpVersion::Any => bindings::CURL_IPRESOLVE_WHATEVER,
+        IpVersion::V4 => bindings::CURL_IPRESOLVE_V4,
+        IpVersion::V6 => bindings::CURL_IPRESOLVE_V6,
+    };
+    curl_setopt_long(
+        curl_client,
+        bindings::CURLOPT_IPRESOLVE,
+        "CURLOPT_IPRESOLVE",
+        ip_resolve,
+    )?;
+
+    curl_setopt_long(
+        curl_client,
+        bindings::CURLOPT_HAPPY_EYEBALLS_TIMEOUT_MS,
+        "CURLOPT_HAPPY_EYEBALLS_TIMEOUT_MS",
+        args.happy_eyeballs_delay_ms
+            .unwrap_or(DEFAULT_HAPPY_EYEBALLS_DELAY_MS) as libc::c_long,
+    )?;
+
+    if let Some(connect_timeout_ms) = args.connect_timeout_ms {
+        curl_setopt_long(
+            curl_client,
+            bindings::CURLOPT_CONNECTTIMEOUT_MS,
+            "CURLOPT_CONNECTTIMEOUT_MS",
+            connect_timeout_ms as libc::c_long,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn curl_setopt_str(
+    curl_client: &bindings::SendableCurl,
+    option: bindings::CURLoption,
+    name: &str,
+    value: &str,
+) -> Result<(), Error> {
+    // curl copies string options, so value can be dropped afterwards
+    let value = CString::new(value).with_context(|| format!("invalid value for {}", name))?;
+    check_err(unsafe {
+        (curl_client.api.curl_easy_setopt)(curl_client.handle, option, value.as_ptr())
+    })
+    .with_context(|| format!("curl_easy_setopt({}) failed", name))
+}
+
+fn curl_setopt_long(
+    curl_client: &bindings::SendableCurl,
+    option: bindings::CURLoption,
+    name: &str,
+    value: libc::c_long,
+) -> Result<(), Error> {
+    check_err(unsafe { (curl_client.api.curl_easy_setopt)(curl_client.handle, option, value) })
+        .with_context(|| format!("curl_easy_setopt({}) failed", name))
+}
+
+fn curl_setopt_slist(
+    curl_client: &mut bindings::SendableCurl,
+    option: bindings::CURLoption,
+    name: &str,
+    values: &[String],
+) -> Result<(), Error> {
+    if values.is_empty() {
+        return Ok(());
+    }
+
+    let values = values
+        .iter()
+        .map(|value| CString::new(value.as_str()))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid value for {}", name))?;
+
+    let mut list = null_mut();
+    for value in &values {
+        list = unsafe { (curl_client.api.curl_slist_append)(list, value.as_ptr()) };
+        assert!(!list.is_null());
+    }
+    // curl_slist_append returns the same head every time, so it is safe to hand off ownership
+    // already
+    curl_client.slists.push(list);
+
+    check_err(unsafe { (curl_client.api.curl_easy_setopt)(curl_client.handle, option, list) })
+        .with_context(|| format!("curl_easy_setopt({}) failed", name))
+}
+
+impl TlsVersion {
+    fn to_curl(self) -> libc::c_long {
+        match self {
+            TlsVersion::Tls1_0 => bindings::CURL_SSLVERSION_TLSv1_0,
+            TlsVersion::Tls1_1 => bindings::CURL_SSLVERSION_TLSv1_1,
+            TlsVersion::Tls1_2 => bindings::CURL_SSLVERSION_TLSv1_2,
+            TlsVersion::Tls1_3 => bindings::CURL_SSLVERSION_TLSv1_3,
+        }
+    }
+}
+
+impl CurlHttpVersion {
+    fn to_curl(self) -> libc::c_long {
+        match self {
+            CurlHttpVersion::Http1_0 => bindings::CURL_HTTP_VERSION_1_0,
+            CurlHttpVersion::Http1_1 => bindings::CURL_HTTP_VERSION_1_1,
+            CurlHttpVersion::Http2 => bindings::CURL_HTTP_VERSION_2_0,
+            CurlHttpVersion::Http2Tls => bindings::CURL_HTTP_VERSION_2TLS,
+            CurlHttpVersion::Http2PriorKnowledge => bindings::CURL_HTTP_VERSION_2_PRIOR_KNOWLEDGE,
+            CurlHttpVersion::Http3 => bindings::CURL_HTTP_VERSION_3,
+        }
+    }
+}
+
 fn curl_get_async_socket(curl_client: &bindings::SendableCurl) -> AsyncFd<i32> {
     let mut socket: bindings::curl_socket_t = 0;
     let res = unsafe {
-        bindings::curl_easy_getinfo(
-            curl_client.0,
+        (curl_client.api.curl_easy_getinfo)(
+            curl_client.handle,
             bindings::CURLINFO_ACTIVESOCKET,
-            (&mut socket) as *mut _,
+            (&mut socket) as *mut bindings::curl_socket_t,
         )
     };
     check_err(res)
@@ -146,3 +1123,21 @@ fn curl_get_async_socket(curl_client: &bindings::SendableCurl) -> AsyncFd<i32> {
         .unwrap();
     AsyncFd::new(socket).unwrap()
 }
+
+/// Check whether the peer has closed the socket and there is nothing left to read.
+///
+/// curl does not reliably report EOF from curl_easy_recv/curl_ws_recv, it sometimes keeps
+/// returning CURLE_AGAIN while the socket stays readable forever. Peeking at the socket tells the
+/// two cases apart.
+fn curl_socket_at_eof(socket: &AsyncFd<i32>) -> bool {
+    let mut byte = 0u8;
+    let rv = unsafe {
+        libc::recv(
+            *socket.get_ref(),
+            (&mut byte) as *mut u8 as *mut libc::c_void,
+            1,
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+    rv == 0
+}
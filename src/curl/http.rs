@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Error};
+use axum::body::Bytes;
+use libc::size_t;
+use tokio::sync::mpsc;
+
+use crate::curl::{
+    bindings, check_err, curl_easy_new, curl_easy_reset, curl_easy_setup, curl_perform,
+    curl_setopt_long, CurlConnector,
+};
+use crate::{CurlCommon, EchCli, PinningCli, ResolverCli};
+
+type ChunkSender = mpsc::Sender<Result<Bytes, Error>>;
+
+/// Handles that finished a request, by the origin they were connected to. curl keeps their
+/// connections open, so reusing them saves the TCP and TLS handshake of the next request.
+type IdleHandles = Mutex<HashMap<String, Vec<bindings::SendableCurl>>>;
+
+/// How many idle handles to keep per origin, the rest are closed.
+const MAX_IDLE_HANDLES: usize = 16;
+
+/// A minimal HTTP client on top of libcurl, so that split-http can use curl's TLS fingerprint.
+///
+/// Every request runs curl_easy_perform on tokio's blocking thread pool, so a download occupies
+/// one of its threads for as long as the session is open. Uploads only hold one while they are
+/// sent.
+#[derive(Clone)]
+pub struct CurlHttpClient {
+    connector: CurlConnector,
+    idle: Arc<IdleHandles>,
+}
+
+impl CurlHttpClient {
+    pub fn new(
+        args: &CurlCommon,
+        resolver: &ResolverCli,
+        pinning: &PinningCli,
+        ech: &EchCli,
+    ) -> Result<Self, Error> {
+        Ok(CurlHttpClient {
+            connector: CurlConnector::new(args, resolver, pinning, ech)?,
+            idle: Default::default(),
+        })
+    }
+
+    /// Send a GET request, and stream the response body through the returned channel.
+    ///
+    /// Dropping the receiver aborts the request.
+    pub fn get(&self, url: String, headers: Vec<String>) -> mpsc::Receiver<Result<Bytes, Error>> {
+        let (sender, receiver) = mpsc::channel(16);
+        let connector = self.connector.clone();
+        let idle = self.idle.clone();
+
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = curl_get(&connector, &idle, &url, &headers, &sender) {
+                let _ = sender.blocking_send(Err(e));
+            }
+        });
+
+        receiver
+    }
+
+    /// Send a POST request with the given body, and discard the response body.
+    pub async fn post(
+        &self,
+        url: String,
+        mut headers: Vec<String>,
+        body: Vec<u8>,
+    ) -> Result<(), Error> {
+        let connector = self.connector.clone();
+        let idle = self.idle.clone();
+
+        // curl adds these by default, but reqwest and browsers do not. Expect: 100-continue
+        // also costs an extra roundtrip.
+        for name in ["Content-Type", "Expect"] {
+            if !headers.iter().any(|header| has_header_name(header, name)) {
+                headers.push(format!("{}:", name));
+            }
+        }
+
+        tokio::task::spawn_blocking(move || curl_post(&connector, &idle, &url, &headers, &body))
+            .await?
+    }
+}
+
+fn has_header_name(header: &str, name: &str) -> bool {
+    header
+        .split_once(':')
+        .is_some_and(|(k, _)| k.trim().eq_ignore_ascii_case(name))
+}
+
+/// The origin of a URL, which idle handles are grouped by.
+fn origin(url: &str) -> String {
+    reqwest::Url::parse(url)
+        .map_or_else(|_| url.to_owned(), |url| url.origin().ascii_serialization())
+}
+
+/// Take an idle handle connected to the origin of url, or create a new one.
+fn take_handle(
+    connector: &CurlConnector,
+    idle: &IdleHandles,
+    url: &str,
+    headers: &[String],
+) -> Result<bindings::SendableCurl, Error> {
+    let handle = idle
+        .lock()
+        .unwrap()
+        .get_mut(&origin(url))
+        .and_then(Vec::pop);
+    match handle {
+        Some(mut curl_client) => {
+            curl_easy_setup(connector, &mut curl_client, url, headers)?;
+            Ok(curl_client)
+        }
+        None => curl_easy_new(connector, url, headers),
+    }
+}
+
+/// Keep a handle after a successful request, for the next request to the same origin. Handles
+/// of failed requests are dropped, which closes their connection.
+fn release_handle(idle: &IdleHandles, url: &str, mut curl_client: bindings::SendableCurl) {
+    // the callbacks and their data point to the finished request
+    curl_easy_reset(&mut curl_client);
+
+    let mut idle = idle.lock().unwrap();
+    let handles = idle.entry(origin(url)).or_default();
+    if handles.len() < MAX_IDLE_HANDLES {
+        handles.push(curl_client);
+    }
+}
+
+fn curl_get(
+    connector: &CurlConnector,
+    idle: &IdleHandles,
+    url: &str,
+    headers: &[String],
+    sender: &ChunkSender,
+) -> Result<(), Error> {
+    let curl_client = take_handle(connector, idle, url, headers)?;
+    let userdata = sender as *const ChunkSender as *mut libc::c_void;
+
+    curl_setopt_long(
+        &curl_client,
+        bindings::CURLOPT_FAILONERROR,
+        "CURLOPT_FAILONERROR",
+        1,
+    )?;
+    unsafe {
+        check_err((curl_client.api.curl_easy_setopt)(
+            curl_client.handle,
+            bindings::CURLOPT_WRITEFUNCTION,
+            send_chunk as bindings::curl_write_callback,
+        ))
+        .context("curl_easy_setopt(CURLOPT_WRITEFUNCTION) failed")?;
+        check_err((curl_client.api.curl_easy_setopt)(
+            curl_client.handle,
+            bindings::CURLOPT_WRITEDATA,
+            userdata,
+        ))
+        .context("curl_easy_setopt(CURLOPT_WRITEDATA) failed")?;
+
+        // the progress callback is called regularly even when no data arrives, and is used to
+        // abort the download once the receiver is gone.
+        check_err((curl_client.api.curl_easy_setopt)(
+            curl_client.handle,
+            bindings::CURLOPT_XFERINFOFUNCTION,
+            check_closed as bindings::curl_xferinfo_callback,
+        ))
+        .context("curl_easy_setopt(CURLOPT_XFERINFOFUNCTION) failed")?;
+        check_err((curl_client.api.curl_easy_setopt)(
+            curl_client.handle,
+            bindings::CURLOPT_XFERINFODATA,
+            userdata,
+        ))
+        .context("curl_easy_setopt(CURLOPT_XFERINFODATA) failed")?;
+    }
+    curl_setopt_long(
+        &curl_client,
+        bindings::CURLOPT_NOPROGRESS,
+        "CURLOPT_NOPROGRESS",
+        0,
+    )?;
+
+    curl_perform(&curl_client).context("GET request failed")?;
+    release_handle(idle, url, curl_client);
+
+    Ok(())
+}
+
+fn curl_post(
+    connector: &CurlConnector,
+    idle: &IdleHandles,
+    url: &str,
+    headers: &[String],
+    body: &[u8],
+) -> Result<(), Error> {
+    let curl_client = take_handle(connector, idle, url, headers)?;
+
+    curl_setopt_long(
+        &curl_client,
+        bindings::CURLOPT_FAILONERROR,
+        "CURLOPT_FAILONERROR",
+        1,
+    )?;
+    curl_setopt_long(&curl_client, bindings::CURLOPT_POST, "CURLOPT_POST", 1)?;
+    unsafe {
+        check_err((curl_client.api.curl_easy_setopt)(
+            curl_client.handle,
+            bindings::CURLOPT_POSTFIELDSIZE_LARGE,
+            body.len() as bindings::curl_off_t,
+        ))
+        .context("curl_easy_setopt(CURLOPT_POSTFIELDSIZE_LARGE) failed")?;
+        // body outlives curl_easy_perform, so curl does not need to copy it
+        check_err((curl_client.api.curl_easy_setopt)(
+            curl_client.handle,
+            bindings::CURLOPT_POSTFIELDS,
+            body.as_ptr(),
+        ))
+        .context("curl_easy_setopt(CURLOPT_POSTFIELDS) failed")?;
+        check_err((curl_client.api.curl_easy_setopt)(
+            curl_client.handle,
+            bindings::CURLOPT_WRITEFUNCTION,
+            discard_chunk as bindings::curl_write_callback,
+        ))
+        .context("curl_easy_setopt(CURLOPT_WRITEFUNCTION) failed")?;
+    }
+
+    curl_perform(&curl_client).context("POST request failed")?;
+    release_handle(idle, url, curl_client);
+
+    Ok(())
+}
+
+extern "C" fn send_chunk(
+    buffer: *const libc::c_char,
+    size: size_t,
+    nitems: size_t,
+    userdata: *mut libc::c_void,
+) -> size_t {
+    let sender = unsafe { &*(userdata as *const ChunkSender) };
+    let len = size * nitems;
+    let chunk =
+        Bytes::copy_from_slice(unsafe { std::slice::from_raw_parts(buffer as *const u8, len) });
+
+    match sender.blocking_send(Ok(chunk)) {
+        Ok(()) => len,
+        // returning less than len aborts the transfer
+        Err(_) => 0,
+    }
+}
+
+extern "C" fn discard_chunk(
+    _buffer: *const libc::c_char,
+    size: size_t,
+    nitems: size_t,
+    _userdata: *mut libc::c_void,
+) -> size_t {
+    size * nitems
+}
+
+extern "C" fn check_closed(
+    userdata: *mut libc::c_void,
+    _dltotal: bindings::curl_off_t,
+    _dlnow: bindings::curl_off_t,
+    _ultotal: bindings::curl_off_t,
+    _ulnow: bindings::curl_off_t,
+) -> libc::c_int {
+    let sender = unsafe { &*(userdata as *const ChunkSender) };
+    // non-zero aborts the transfer
+    sender.is_closed() as libc::c_int
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use clap::Parser;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{Cli, CliSubcommand};
+
+    /// Start an HTTP/1.1 server that answers GET with "hello" and everything else with an empty
+    /// body, and counts the connections it accepted.
+    async fn http_server() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut socket = BufReader::new(socket);
+                    loop {
+                        let mut request_line = String::new();
+                        if socket.read_line(&mut request_line).await.unwrap() == 0 {
+                            return;
+                        }
+                        let mut content_length = 0;
+                        loop {
+                            let mut line = String::new();
+                            socket.read_line(&mut line).await.unwrap();
+                            if line == "\r\n" {
+                                break;
+                            }
+                            if let Some((name, value)) = line.split_once(':') {
+                                if name.eq_ignore_ascii_case("content-length") {
+                                    content_length = value.trim().parse().unwrap();
+                                }
+                            }
+                        }
+                        let mut body = vec![0; content_length];
+                        socket.read_exact(&mut body).await.unwrap();
+
+                        let body = if request_line.starts_with("GET") {
+                            "hello"
+                        } else {
+                            ""
+                        };
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                            body.len(),
+                            body
+                        );
+                        socket.write_all(response.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        (url, connections)
+    }
+
+    #[tokio::test]
+    async fn test_reuse_connections() {
+        let (url, connections) = http_server().await;
+        let cli = Cli::parse_from(["minidialer", "split-http", "--backend", "curl", &url]);
+        let CliSubcommand::SplitHttp(args) = cli.command else {
+            unreachable!()
+        };
+        let client =
+            CurlHttpClient::new(&args.curl, &args.resolver, &args.pinning, &args.ech).unwrap();
+
+        for i in 0..3 {
+            client
+                .post(format!("{}/session/{}", url, i), Vec::new(), vec![1, 2, 3])
+                .await
+                .unwrap();
+        }
+        let mut download = client.get(format!("{}/session", url), Vec::new());
+        assert_eq!(&download.recv().await.unwrap().unwrap()[..], b"hello");
+        assert!(download.recv().await.is_none());
+        client
+            .post(format!("{}/session/3", url), Vec::new(), Vec::new())
+            .await
+            .unwrap();
+
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        assert_eq!(client.idle.lock().unwrap()[&origin(&url)].len(), 1);
+    }
+}
@@ -7,11 +7,11 @@ use tokio::net::TcpStream;
 use anyhow::{Context, Error};
 
 use crate::{
-    curl::{bindings, check_err, curl_connect_only, curl_get_async_socket},
+    curl::{bindings, check_err, curl_get_async_socket, curl_socket_at_eof, CurlConnector},
     CurlTcpCli,
 };
 
-pub async fn main(args: CurlTcpCli) {
+pub async fn main(args: CurlTcpCli) -> Result<(), Error> {
     let addr = format!("{}:{}", args.common.host, args.common.port);
     tracing::info!("listening on {}, forwarding to {:?}", addr, args.upstream);
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
@@ -23,66 +23,158 @@ pub async fn main(args: CurlTcpCli) {
         format!("ws://{}", args.upstream)
     };
 
+    let connector = CurlConnector::new(&args.curl, &args.resolver, &args.pinning, &args.ech)?;
+
     loop {
         let (socket, _) = listener.accept().await.unwrap();
         let upstream = upstream.clone();
+        let connector = connector.clone();
+        let buffer_size = args.buffer_size;
         tokio::spawn(async move {
-            if let Err(e) = process_connection(socket, upstream).await {
+            if let Err(e) = process_connection(socket, upstream, connector, buffer_size).await {
                 tracing::warn!("closed connection: {:?}", e);
             }
         });
     }
 }
 
-async fn process_connection(mut socket: TcpStream, upstream: String) -> Result<(), Error> {
-    let curl_client = curl_connect_only(&upstream, 1).context("curl_connect_only failed")?;
+/// Data that was read from one side of the relay and still has to be written to the other.
+///
+/// The buffer is only refilled once it has been written out completely.
+struct RelayBuffer {
+    data: Box<[u8]>,
+    start: usize,
+    end: usize,
+}
+
+impl RelayBuffer {
+    fn new(size: usize) -> Self {
+        RelayBuffer {
+            data: vec![0; size].into_boxed_slice(),
+            start: 0,
+            end: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    fn pending(&self) -> &[u8] {
+        &self.data[self.start..self.end]
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.start += n;
+        if self.is_empty() {
+            self.start = 0;
+            self.end = 0;
+        }
+    }
+
+    /// The whole buffer, to read into. Only call when empty.
+    fn space(&mut self) -> &mut [u8] {
+        debug_assert!(self.is_empty());
+        &mut self.data
+    }
+
+    fn filled(&mut self, n: usize) {
+        self.start = 0;
+        self.end = n;
+    }
+}
+
+/// Relay between the client and curl in both directions at once.
+///
+/// Each direction has its own buffer. Every iteration makes as much nonblocking progress as
+/// possible on both, and only waits for readiness once nothing can be done without blocking.
+async fn process_connection(
+    mut socket: TcpStream,
+    upstream: String,
+    connector: CurlConnector,
+    buffer_size: usize,
+) -> Result<(), Error> {
+    let curl_client = connector
+        .connect_only(upstream, 1, Vec::new())
+        .await
+        .context("curl_connect_only failed")?;
     let curl_socket = curl_get_async_socket(&curl_client);
 
-    let mut buffer: [u8; 2048] = [0; 2048];
-    let mut to_curl_send = 0;
-    let mut to_client_send = 0;
+    // client -> curl
+    let mut upload = RelayBuffer::new(buffer_size);
+    // curl -> client
+    let mut download = RelayBuffer::new(buffer_size);
+    let mut client_eof = false;
+    let mut curl_eof = false;
 
     loop {
-        if to_client_send > 0 {
-            tracing::debug!("sending {} bytes to client", to_client_send);
-            socket
-                .write_all(&buffer[..to_client_send])
-                .await
-                .context("socket send failed")?;
-            to_client_send = 0;
-        } else if to_curl_send > 0 {
-            tracing::debug!("sending {} bytes to curl", to_curl_send);
-            let mut send_buffer = &mut buffer[..to_curl_send];
-
-            while send_buffer.len() > 0 {
-                let mut sent: size_t = 0;
-                tracing::debug!("curl_easy_send");
-                check_err(unsafe {
-                    bindings::curl_easy_send(
-                        curl_client.0,
-                        send_buffer.as_mut_ptr(),
-                        send_buffer.len(),
-                        (&mut sent) as *mut _,
-                    )
-                })
-                .context("curl_easy_send failed")?;
-
-                send_buffer = &mut send_buffer[sent..];
+        let mut progress = false;
+
+        if !upload.is_empty() {
+            let pending = upload.pending();
+            let mut sent: size_t = 0;
+            check_err(unsafe {
+                (curl_client.api.curl_easy_send)(
+                    curl_client.handle,
+                    pending.as_ptr(),
+                    pending.len(),
+                    (&mut sent) as *mut _,
+                )
+            })
+            .context("curl_easy_send failed")?;
+
+            if sent > 0 {
+                tracing::debug!("sent {} bytes to curl", sent);
+                upload.consume(sent);
+                progress = true;
             }
+        }
 
-            to_curl_send = 0;
-        } else {
-            let mut bytes_received: size_t = 0;
+        if !download.is_empty() {
+            match socket.try_write(download.pending()) {
+                Ok(sent) => {
+                    tracing::debug!("sent {} bytes to client", sent);
+                    download.consume(sent);
+                    progress = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e).context("socket send failed"),
+            }
+        }
+
+        if upload.is_empty() && !client_eof {
+            match socket.try_read(upload.space()) {
+                Ok(0) => {
+                    tracing::debug!("client closed, closing write half of curl socket");
+                    client_eof = true;
+                    // curl has no API to shut down a CONNECT_ONLY connection. this skips TLS
+                    // close_notify, but still sends FIN to the server.
+                    unsafe {
+                        libc::shutdown(*curl_socket.get_ref(), libc::SHUT_WR);
+                    }
+                    progress = true;
+                }
+                Ok(read) => {
+                    tracing::debug!("read {} bytes from client", read);
+                    upload.filled(read);
+                    progress = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e).context("socket read failed"),
+            }
+        }
 
-            tracing::debug!("curl_easy_recv");
-            // XXX: this hangs after the connection is terminated by the server
-            // ideally it would error, like curl_easy_send
+        // curl may have decrypted data buffered that the socket does not signal readiness for
+        // anymore, so this is tried before waiting on the socket.
+        if download.is_empty() && !curl_eof {
+            let mut bytes_received: size_t = 0;
+            let space = download.space();
             let res = unsafe {
                 // nonblocking
-                bindings::curl_easy_recv(
-                    curl_client.0,
-                    (&mut buffer) as *mut _,
-                    buffer.len(),
+                (curl_client.api.curl_easy_recv)(
+                    curl_client.handle,
+                    space.as_mut_ptr(),
+                    space.len(),
                     (&mut bytes_received) as *mut _,
                 )
             };
@@ -90,34 +182,181 @@ async fn process_connection(mut socket: TcpStream, upstream: String) -> Result<(
             check_err(res).context("curl_easy_recv failed")?;
 
             if bytes_received > 0 {
-                to_client_send = bytes_received;
-            } else if res == bindings::CURLE_AGAIN {
-                // sometimes curl returns no data to read but does not return EAGAIN. in this case
-                // we still should do something other than spinning on curl_easy_recv
-                tracing::debug!("curl_easy_recv res = {}", res);
-
-                tracing::debug!("selecting");
-                tokio::select! {
-                    guard = curl_socket.readable() => {
-                        tracing::debug!("select: curl socket ready");
-                        guard.unwrap().clear_ready() },
-                    readable_res = socket.readable() => {
-                        tracing::debug!("select: client socket ready");
-                        readable_res.context("selecting client socket failed")?;
-                        to_curl_send = match socket.try_read(&mut buffer) {
-                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => 0,
-                            Ok(0) => {
-                                tracing::debug!("received zero-read after readiness on client socket, closing");
-                                return Ok(());
-                            }
-                            res => res.context("socket read failed")?
-                        };
-                        tracing::debug!("read {} bytes from client socket", to_curl_send);
-                    }
+                tracing::debug!("read {} bytes from curl", bytes_received);
+                download.filled(bytes_received);
+                progress = true;
+            } else if res == bindings::CURLE_OK {
+                // a zero-byte read without EAGAIN means the server closed the connection
+                tracing::debug!("curl_easy_recv returned EOF");
+                curl_eof = true;
+                socket.shutdown().await.context("socket shutdown failed")?;
+                progress = true;
+            }
+        }
+
+        if client_eof && curl_eof && upload.is_empty() && download.is_empty() {
+            tracing::debug!("both sides closed, closing connection");
+            return Ok(());
+        }
+
+        if progress {
+            continue;
+        }
+
+        tracing::debug!("selecting");
+        tokio::select! {
+            res = socket.readable(), if upload.is_empty() && !client_eof => {
+                res.context("selecting client socket failed")?;
+            },
+            res = socket.writable(), if !download.is_empty() => {
+                res.context("selecting client socket failed")?;
+            },
+            guard = curl_socket.readable(), if download.is_empty() && !curl_eof => {
+                let mut guard = guard.context("selecting curl socket failed")?;
+                if curl_socket_at_eof(&curl_socket) {
+                    tracing::debug!("curl socket is readable but at EOF");
+                    curl_eof = true;
+                    socket
+                        .shutdown()
+                        .await
+                        .context("socket shutdown failed")?;
+                } else {
+                    guard.clear_ready();
                 }
-            } else {
-                return Ok(());
+            },
+            guard = curl_socket.writable(), if !upload.is_empty() => {
+                guard.context("selecting curl socket failed")?.clear_ready();
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use clap::Parser;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{Cli, CliSubcommand};
+
+    const BUFFER_SIZE: usize = 65536;
+
+    fn connector() -> CurlConnector {
+        let cli = Cli::parse_from(["minidialer", "curl-tcp", "--no-tls", "unused"]);
+        let CliSubcommand::CurlTcp(args) = cli.command else {
+            unreachable!()
+        };
+        CurlConnector::new(&args.curl, &args.resolver, &args.pinning, &args.ech).unwrap()
+    }
+
+    #[test]
+    fn test_buffer_size() {
+        let parse = |size: &str| {
+            Cli::try_parse_from(["minidialer", "curl-tcp", "--buffer-size", size, "unused"])
+        };
+        assert!(parse("1").is_ok());
+        // an empty buffer would look like the client closed the connection
+        assert!(parse("0").is_err());
+    }
+
+    /// Start a server that echoes everything back, and closes its write half after the client
+    /// closed.
+    async fn echo_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = socket.split();
+                    tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+                    writer.shutdown().await.unwrap();
+                });
             }
+        });
+        addr.to_string()
+    }
+
+    /// Start a relay in front of the echo server, either through curl or copy_bidirectional.
+    async fn relay(upstream: String, use_curl: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connector = connector();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let upstream = upstream.clone();
+                let connector = connector.clone();
+                tokio::spawn(async move {
+                    if use_curl {
+                        // plain http:// works with any libcurl, ws:// needs websocket support
+                        let url = format!("http://{}", upstream);
+                        process_connection(socket, url, connector, BUFFER_SIZE)
+                            .await
+                            .unwrap();
+                    } else {
+                        let mut upstream = TcpStream::connect(upstream).await.unwrap();
+                        tokio::io::copy_bidirectional(&mut socket, &mut upstream)
+                            .await
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        addr.to_string()
+    }
+
+    /// Send data through the relay in one direction while reading the echo in the other, and
+    /// return the echoed data.
+    async fn roundtrip(relay: &str, data: Vec<u8>) -> Vec<u8> {
+        let socket = TcpStream::connect(relay).await.unwrap();
+        let (mut reader, mut writer) = socket.into_split();
+        let write = tokio::spawn(async move {
+            writer.write_all(&data).await.unwrap();
+            writer.shutdown().await.unwrap();
+        });
+
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        write.await.unwrap();
+        received
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_relay() {
+        let relay = relay(echo_server().await, true).await;
+        let data = (0..1024 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+
+        assert_eq!(roundtrip(&relay, data.clone()).await, data);
+        // half-close without any data
+        assert_eq!(roundtrip(&relay, Vec::new()).await, Vec::<u8>::new());
+    }
+
+    /// Compare throughput of the curl relay with tokio's copy_bidirectional, over plaintext
+    /// loopback connections. Run with:
+    ///
+    /// cargo test --release bench_relay_throughput -- --ignored --nocapture
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_relay_throughput() {
+        let upstream = echo_server().await;
+        let data = vec![0x42; 256 * 1024 * 1024];
+
+        for (name, use_curl) in [("copy_bidirectional", false), ("curl", true)] {
+            let relay = relay(upstream.clone(), use_curl).await;
+            let start = Instant::now();
+            let received = roundtrip(&relay, data.clone()).await;
+            let elapsed = start.elapsed();
+
+            assert_eq!(received.len(), data.len());
+            println!(
+                "{}: {:.0} MB/s in each direction",
+                name,
+                data.len() as f64 / elapsed.as_secs_f64() / 1e6
+            );
         }
     }
 }
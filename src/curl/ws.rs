@@ -5,19 +5,27 @@ use axum::extract::ws::{Message, WebSocket};
 use axum::extract::{State, WebSocketUpgrade};
 use axum::http::Uri;
 use axum::Router;
+use futures::{SinkExt, StreamExt};
 use libc::size_t;
+use tokio::sync::mpsc;
 
-use crate::curl::{bindings, check_err, curl_connect_only, curl_get_async_socket};
+use crate::curl::{bindings, check_err, curl_get_async_socket, curl_socket_at_eof, CurlConnector};
 use crate::CurlWsCli;
 
 #[derive(Clone)]
 struct AppState {
     upstream: String,
+    headers: Vec<String>,
+    connector: CurlConnector,
+    buffer_size: usize,
 }
 
 pub async fn main(args: CurlWsCli) -> Result<(), Error> {
     let state = AppState {
         upstream: args.upstream.clone(),
+        headers: args.header.clone(),
+        connector: CurlConnector::new(&args.curl, &args.resolver, &args.pinning, &args.ech)?,
+        buffer_size: args.buffer_size,
     };
 
     let app = Router::new()
@@ -34,7 +42,7 @@ pub async fn main(args: CurlWsCli) -> Result<(), Error> {
 
     Ok(())
 }
-async fn curl_handler(State(state): State<AppState>, uri: Uri, mut socket: WebSocket) {
+async fn curl_handler(State(state): State<AppState>, uri: Uri, socket: WebSocket) {
     let dialer_url = format!(
         "{}{}",
         state.upstream,
@@ -45,7 +53,11 @@ async fn curl_handler(State(state): State<AppState>, uri: Uri, mut socket: WebSo
 
     tracing::debug!("connecting to {}", dialer_url);
 
-    let curl_client = match curl_connect_only(&dialer_url, 2) {
+    let curl_client = match state
+        .connector
+        .connect_only(dialer_url, 2, state.headers.clone())
+        .await
+    {
         Ok(x) => x,
         Err(e) => {
             tracing::warn!("curl_easy_perform failed: {}", e);
@@ -55,88 +67,183 @@ async fn curl_handler(State(state): State<AppState>, uri: Uri, mut socket: WebSo
 
     let curl_socket = curl_get_async_socket(&curl_client);
 
-    let mut to_curl_send: Option<Message> = None;
+    // writing to the client happens in a separate task, so that a slow client does not stop
+    // data from flowing in the other direction
+    let (mut client_sink, mut client_stream) = socket.split();
+    let (client_sender, mut client_receiver) = mpsc::channel::<Message>(16);
+    tokio::spawn(async move {
+        while let Some(msg) = client_receiver.recv().await {
+            if client_sink.send(msg).await.is_err() {
+                tracing::debug!("failed to forward data from upstream, dropping connection");
+                return;
+            }
+        }
+    });
+
+    let mut buffer = vec![0u8; state.buffer_size];
+    // client -> curl, and how much of it was already sent
+    let mut to_curl_send: Option<(Vec<u8>, usize)> = None;
+    // curl -> client, waiting for space in the channel
     let mut to_client_send: Option<Message> = None;
 
     loop {
-        if let Some(to_send) = to_client_send.take() {
-            tracing::debug!("sending to client");
-            if socket.send(to_send).await.is_err() {
-                tracing::debug!("failed to forward data from upstream, dropping connection");
+        let mut progress = false;
+        let mut curl_blocked = false;
+
+        if let Some((ref data, ref mut offset)) = to_curl_send {
+            let send_buffer = &data[*offset..];
+            let mut sent: size_t = 0;
+            tracing::debug!("curl_ws_send");
+            let res = unsafe {
+                (curl_client.api.curl_ws_send)(
+                    curl_client.handle,
+                    send_buffer.as_ptr(),
+                    send_buffer.len(),
+                    (&mut sent) as *mut _,
+                    0,
+                    bindings::CURLWS_BINARY,
+                )
+            };
+
+            if let Err(e) = check_err(res) {
+                tracing::warn!("curl_ws_send failed: {}", e);
                 return;
             }
-        } else if let Some(to_send) = to_curl_send.take() {
-            tracing::debug!("sending to curl");
-            let mut to_send = to_send.into_data();
-            let mut send_buffer = to_send.as_mut_slice();
-
-            while send_buffer.len() > 0 {
-                let mut sent: size_t = 0;
-                tracing::debug!("curl_ws_send");
-                let res = unsafe {
-                    bindings::curl_ws_send(
-                        curl_client.0,
-                        send_buffer.as_mut_ptr(),
-                        send_buffer.len(),
-                        (&mut sent) as *mut _,
-                        0,
-                        bindings::CURLWS_BINARY,
-                    )
-                };
 
-                if let Err(e) = check_err(res) {
-                    tracing::warn!("curl_ws_send failed: {}", e);
-                    return;
-                }
+            *offset += sent;
+            if *offset == data.len() {
+                to_curl_send = None;
+                progress = true;
+            } else if sent > 0 {
+                progress = true;
+            } else {
+                curl_blocked = true;
+            }
+        }
 
-                send_buffer = &mut send_buffer[sent..];
+        if let Some(msg) = to_client_send.take() {
+            match client_sender.try_send(msg) {
+                Ok(()) => progress = true,
+                Err(mpsc::error::TrySendError::Full(msg)) => to_client_send = Some(msg),
+                Err(mpsc::error::TrySendError::Closed(_)) => return,
             }
-        } else {
+        }
+
+        // curl may have decrypted data buffered that the socket does not signal readiness for
+        // anymore, so this is tried before waiting on the socket.
+        if to_client_send.is_none() {
             let mut bytes_received: size_t = 0;
-            let mut buffer: [u8; 2048] = [0; 2048];
 
             tracing::debug!("curl_ws_recv");
-            // XXX: this hangs after the connection is terminated by the server
-            // ideally it would error, like curl_ws_send
-            let res = unsafe {
+            let (res, frame_flags) = unsafe {
                 let mut meta: *mut bindings::curl_ws_frame = null_mut();
 
                 // nonblocking
-                bindings::curl_ws_recv(
-                    curl_client.0,
-                    (&mut buffer) as *mut _,
+                let res = (curl_client.api.curl_ws_recv)(
+                    curl_client.handle,
+                    buffer.as_mut_ptr(),
                     buffer.len(),
                     (&mut bytes_received) as *mut _,
                     (&mut meta) as *mut _,
-                )
+                );
+
+                let frame_flags = if meta.is_null() {
+                    None
+                } else {
+                    Some((*meta).flags as libc::c_uint)
+                };
+
+                (res, frame_flags)
             };
 
+            if res == bindings::CURLE_GOT_NOTHING {
+                tracing::debug!("curl_ws_recv: server closed the connection");
+                let _ = client_sender.send(Message::Close(None)).await;
+                return;
+            }
+
             if let Err(e) = check_err(res) {
                 tracing::warn!("curl_ws_recv failed: {}", e);
                 return;
             }
 
-            if bytes_received > 0 {
+            if frame_flags.is_some_and(|flags| flags & bindings::CURLWS_CLOSE != 0) {
+                tracing::debug!("curl_ws_recv: received close frame");
+                let _ = client_sender.send(Message::Close(None)).await;
+                return;
+            } else if bytes_received > 0 {
                 to_client_send = Some(Message::Binary(buffer[..bytes_received].to_vec()));
-            } else if res == bindings::CURLE_AGAIN {
-                tracing::debug!("selecting");
-                tokio::select! {
-                    guard = curl_socket.readable() => { guard.unwrap().clear_ready() },
-                    msg = socket.recv() => {
-                        match msg {
-                            Some(Ok(msg)) if !matches!(msg, Message::Close(_)) => {
-                                if matches!(msg, Message::Ping(_) | Message::Pong(_)) {
-                                    tracing::debug!("skipping non-payload message");
-                                } else {
-                                    tracing::debug!("to_curl_send set to {:?}", msg);
-                                    to_curl_send = Some(msg);
-                                }
-                            }
-                            _ => {
-                                tracing::debug!("websocket closed, dropping channel");
-                                return;
-                            }
+                progress = true;
+            } else if res == bindings::CURLE_OK && frame_flags.is_none() {
+                // a zero-byte read without EAGAIN and without a frame means the server closed
+                // the connection
+                tracing::debug!("curl_ws_recv returned EOF");
+                let _ = client_sender.send(Message::Close(None)).await;
+                return;
+            }
+        }
+
+        if progress {
+            continue;
+        }
+
+        tracing::debug!("selecting");
+        tokio::select! {
+            permit = client_sender.reserve(), if to_client_send.is_some() => {
+                let Ok(permit) = permit else {
+                    return;
+                };
+                permit.send(to_client_send.take().unwrap());
+            },
+            guard = curl_socket.readable(), if to_client_send.is_none() => {
+                let Ok(mut guard) = guard else {
+                    tracing::warn!("selecting curl socket failed");
+                    return;
+                };
+
+                if curl_socket_at_eof(&curl_socket) {
+                    tracing::debug!("curl socket is readable but at EOF");
+                    let _ = client_sender.send(Message::Close(None)).await;
+                    return;
+                }
+
+                guard.clear_ready();
+            },
+            guard = curl_socket.writable(), if curl_blocked => {
+                let Ok(mut guard) = guard else {
+                    tracing::warn!("selecting curl socket failed");
+                    return;
+                };
+                guard.clear_ready();
+            },
+            msg = client_stream.next(), if to_curl_send.is_none() => {
+                match msg {
+                    Some(Ok(msg)) if !matches!(msg, Message::Close(_)) => {
+                        if matches!(msg, Message::Ping(_) | Message::Pong(_)) {
+                            tracing::debug!("skipping non-payload message");
+                        } else {
+                            tracing::debug!("to_curl_send set to {:?}", msg);
+                            to_curl_send = Some((msg.into_data(), 0));
+                        }
+                    }
+                    _ => {
+                        tracing::debug!("websocket closed, sending close frame upstream");
+                        let mut sent: size_t = 0;
+                        let res = unsafe {
+                            (curl_client.api.curl_ws_send)(
+                                curl_client.handle,
+                                [].as_ptr(),
+                                0,
+                                (&mut sent) as *mut _,
+                                0,
+                                bindings::CURLWS_CLOSE,
+                            )
+                        };
+
+                        if let Err(e) = check_err(res) {
+                            tracing::debug!("failed to send close frame: {}", e);
                         }
+                        return;
                     }
                 }
             }
@@ -0,0 +1,352 @@
+use std::sync::Mutex;
+
+use anyhow::{Context, Error};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use rustls::client::{EchConfig, EchMode};
+use rustls::crypto::aws_lc_rs::hpke::ALL_SUPPORTED_SUITES;
+use rustls::pki_types::EchConfigListBytes;
+
+use crate::resolver::Resolver;
+use crate::EchCli;
+
+/// Where the ECHConfigList of the upstream comes from, from --ech and --ech-config.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ech {
+    /// Look up the HTTPS record of the upstream using DoH.
+    Dns,
+    /// A decoded ECHConfigList from --ech-config.
+    Static(Vec<u8>),
+}
+
+impl Ech {
+    /// Returns None if ECH is not enabled.
+    pub fn from_args(args: &EchCli) -> Result<Option<Ech>, Error> {
+        if let Some(ref config) = args.ech_config {
+            let config = BASE64_STANDARD
+                .decode(config.trim())
+                .context("invalid --ech-config, expected base64")?;
+            Ok(Some(Ech::Static(config)))
+        } else if args.ech {
+            Ok(Some(Ech::Dns))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Values for CURLOPT_ECH, in the order they have to be set. curl looks up the HTTPS record
+    /// by itself, which only works with CURLOPT_DOH_URL.
+    #[cfg(feature = "curl")]
+    pub fn curl_options(&self) -> Vec<String> {
+        // hard means that curl does not fall back to a plaintext SNI
+        let mut options = vec!["hard".to_owned()];
+        if let Ech::Static(ref config) = self {
+            options.push(format!("ecl:{}", BASE64_STANDARD.encode(config)));
+        }
+        options
+    }
+
+    /// The ECHConfigList for connections to host, looking it up if necessary.
+    async fn config_list(&self, resolver: &Resolver, host: &str) -> Result<Vec<u8>, Error> {
+        match self {
+            Ech::Dns => resolver.lookup_ech_config(host).await,
+            Ech::Static(config) => Ok(config.clone()),
+        }
+    }
+}
+
+/// The rustls ECH mode for connections to host.
+fn rustls_mode(config_list: Vec<u8>, host: &str) -> Result<EchMode, Error> {
+    let config = EchConfig::new(EchConfigListBytes::from(config_list), ALL_SUPPORTED_SUITES)
+        .with_context(|| format!("no usable ECH config for {}", host))?;
+    Ok(EchMode::Enable(config))
+}
+
+/// Something built from the rustls ECH mode for an upstream, like a TLS config or an HTTP
+/// client. With --ech, the HTTPS record is looked up again once its TTL expired, and the value
+/// is rebuilt when the server rotated its ECH keys in the meantime.
+pub struct EchRotation<T> {
+    ech: Option<Ech>,
+    resolver: Resolver,
+    host: String,
+    build: Box<dyn Fn(Option<EchMode>) -> Result<T, Error> + Send + Sync>,
+    /// The value and the ECHConfigList it was built with.
+    current: Mutex<Option<(Option<Vec<u8>>, T)>>,
+}
+
+impl<T: Clone> EchRotation<T> {
+    /// Builds the first value right away, so that a missing or broken config is reported at
+    /// startup.
+    pub async fn new(
+        ech: Option<Ech>,
+        resolver: Resolver,
+        host: String,
+        build: impl Fn(Option<EchMode>) -> Result<T, Error> + Send + Sync + 'static,
+    ) -> Result<Self, Error> {
+        let rotation = EchRotation {
+            ech,
+            resolver,
+            host,
+            build: Box::new(build),
+            current: Mutex::new(None),
+        };
+        rotation.current().await?;
+        Ok(rotation)
+    }
+
+    /// The value for the current ECH config.
+    pub async fn current(&self) -> Result<T, Error> {
+        let config_list = match self.ech {
+            Some(ref ech) => Some(ech.config_list(&self.resolver, &self.host).await?),
+            None => None,
+        };
+
+        let mut current = self.current.lock().unwrap();
+        match *current {
+            Some((ref built_with, ref value)) if *built_with == config_list => {
+                return Ok(value.clone());
+            }
+            Some(_) => tracing::info!("ECH config of {} changed", self.host),
+            None => {}
+        }
+
+        let mode = match config_list {
+            Some(ref config_list) => Some(rustls_mode(config_list.clone(), &self.host)?),
+            None => None,
+        };
+        let value = (self.build)(mode)?;
+        *current = Some((config_list, value.clone()));
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use rustls::crypto::aws_lc_rs::hpke::DH_KEM_X25519_HKDF_SHA256_AES_128;
+    use rustls::crypto::hpke::{EncapsulatedSecret, Hpke, HpkePrivateKey};
+    use tokio::io::AsyncReadExt;
+
+    use crate::client_hello::{ClientHello, RECORD_HEADER_LEN};
+    use crate::pin;
+
+    const EXT_ECH: u16 = 0xfe0d;
+
+    /// Build an ECHConfigList with a single config for public_name, and return it together with
+    /// the ECHConfig that is part of the HPKE info.
+    fn build_config_list(public_key: &[u8], public_name: &str) -> (Vec<u8>, Vec<u8>) {
+        let mut contents = vec![7]; // config_id
+        contents.extend_from_slice(&0x0020u16.to_be_bytes()); // DHKEM(X25519, HKDF-SHA256)
+        contents.extend_from_slice(&(public_key.len() as u16).to_be_bytes());
+        contents.extend_from_slice(public_key);
+        contents.extend_from_slice(&4u16.to_be_bytes());
+        contents.extend_from_slice(&[0, 1, 0, 1]); // HKDF-SHA256, AES-128-GCM
+        contents.push(0); // maximum_name_length
+        contents.push(public_name.len() as u8);
+        contents.extend_from_slice(public_name.as_bytes());
+        contents.extend_from_slice(&[0, 0]); // no extensions
+
+        let mut config = EXT_ECH.to_be_bytes().to_vec();
+        config.extend_from_slice(&(contents.len() as u16).to_be_bytes());
+        config.extend_from_slice(&contents);
+
+        let mut list = (config.len() as u16).to_be_bytes().to_vec();
+        list.extend_from_slice(&config);
+        (list, config)
+    }
+
+    /// Return the range of the ECH extension's data in a ClientHello record.
+    fn find_ech_extension(record: &[u8]) -> std::ops::Range<usize> {
+        let u16_at = |pos: usize| u16::from_be_bytes([record[pos], record[pos + 1]]) as usize;
+        // record header, handshake header, version, random
+        let mut pos = RECORD_HEADER_LEN + 4 + 2 + 32;
+        pos += 1 + record[pos] as usize; // session id
+        pos += 2 + u16_at(pos); // cipher suites
+        pos += 1 + record[pos] as usize; // compression methods
+        pos += 2;
+        while pos < record.len() {
+            let (ext_type, len) = (u16_at(pos), u16_at(pos + 2));
+            if ext_type == EXT_ECH as usize {
+                return pos + 4..pos + 4 + len;
+            }
+            pos += 4 + len;
+        }
+        panic!("no ECH extension");
+    }
+
+    /// A stand-in for an ECH-enabled TLS server: accepts one connection, reads the ClientHello,
+    /// decrypts the inner ClientHello and returns (outer record, decrypted inner ClientHello).
+    async fn ech_stand_in(
+        listener: tokio::net::TcpListener,
+        private_key: HpkePrivateKey,
+        config: Vec<u8>,
+    ) -> (Vec<u8>, Vec<u8>) {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut record = vec![0; RECORD_HEADER_LEN];
+        socket.read_exact(&mut record).await.unwrap();
+        let len = u16::from_be_bytes([record[3], record[4]]) as usize;
+        record.resize(RECORD_HEADER_LEN + len, 0);
+        socket
+            .read_exact(&mut record[RECORD_HEADER_LEN..])
+            .await
+            .unwrap();
+
+        // ECHClientHello: type, kdf, aead, config_id, enc, payload
+        let ext = find_ech_extension(&record);
+        assert_eq!(record[ext.start], 0, "not an outer ClientHello");
+        let enc_len = u16::from_be_bytes([record[ext.start + 6], record[ext.start + 7]]) as usize;
+        let enc = record[ext.start + 8..ext.start + 8 + enc_len].to_vec();
+        let payload = ext.start + 8 + enc_len + 2..ext.end;
+
+        // the AAD is the outer ClientHello with the payload zeroed
+        let mut aad = record[RECORD_HEADER_LEN + 4..].to_vec();
+        let aad_payload =
+            payload.start - RECORD_HEADER_LEN - 4..payload.end - RECORD_HEADER_LEN - 4;
+        aad[aad_payload].fill(0);
+
+        let info = [&b"tls ech\0"[..], &config].concat();
+        let inner = DH_KEM_X25519_HKDF_SHA256_AES_128
+            .setup_opener(&EncapsulatedSecret(enc), &info, &private_key)
+            .unwrap()
+            .open(&aad, &record[payload])
+            .unwrap();
+
+        (record, inner)
+    }
+
+    #[test]
+    fn test_from_args() {
+        let ech = Ech::from_args(&EchCli {
+            ech: false,
+            ech_config: Some("AQID".to_owned()),
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(ech, Ech::Static(vec![1, 2, 3]));
+        #[cfg(feature = "curl")]
+        assert_eq!(ech.curl_options(), vec!["hard", "ecl:AQID"]);
+
+        let ech = Ech::from_args(&EchCli {
+            ech: true,
+            ech_config: None,
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(ech, Ech::Dns);
+        #[cfg(feature = "curl")]
+        assert_eq!(ech.curl_options(), vec!["hard"]);
+
+        assert!(Ech::from_args(&EchCli {
+            ech: false,
+            ech_config: Some("not base64!".to_owned()),
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rustls_ech() {
+        let (public_key, private_key) = DH_KEM_X25519_HKDF_SHA256_AES_128
+            .generate_key_pair()
+            .unwrap();
+        let (config_list, config) = build_config_list(&public_key.0, "public.test");
+
+        let mode = rustls_mode(config_list, "secret.test").unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stand_in = tokio::spawn(ech_stand_in(listener, private_key, config));
+
+        let client = reqwest::Client::builder()
+            .use_preconfigured_tls(pin::rustls_config(Vec::new(), Some(mode)).unwrap())
+            .resolve("secret.test", addr)
+            .build()
+            .unwrap();
+        // the stand-in never completes the handshake
+        client
+            .get(format!("https://secret.test:{}/", addr.port()))
+            .send()
+            .await
+            .unwrap_err();
+
+        let (outer, inner) = stand_in.await.unwrap();
+        let hello = ClientHello::parse(&outer).unwrap();
+        assert_eq!(hello.sni(&outer), Some("public.test"));
+        assert!(!outer.windows(11).any(|w| w == b"secret.test"));
+        assert!(inner.windows(11).any(|w| w == b"secret.test"));
+    }
+
+    #[tokio::test]
+    async fn test_ech_rotation() {
+        // a DoH server that answers HTTPS queries with the current config list and TTL
+        let record = Arc::new(Mutex::new((Vec::new(), 0u32)));
+        let doh = {
+            let record = record.clone();
+            move |query: axum::body::Bytes| async move {
+                let (ref config_list, ttl) = *record.lock().unwrap();
+                // priority 1, target name ".", ech=config_list
+                let mut rdata = vec![0, 1, 0, 0, 5];
+                rdata.extend_from_slice(&(config_list.len() as u16).to_be_bytes());
+                rdata.extend_from_slice(config_list);
+
+                let mut response = query.to_vec();
+                response[2..4].copy_from_slice(&[0x81, 0x80]);
+                response[6..8].copy_from_slice(&[0, 1]);
+                response.extend_from_slice(&[0xc0, 12, 0, 65, 0, 1]);
+                response.extend_from_slice(&ttl.to_be_bytes());
+                response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+                response.extend_from_slice(&rdata);
+                response
+            }
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/dns-query", listener.local_addr().unwrap());
+        let app = axum::Router::new().route("/dns-query", axum::routing::post(doh));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let key = || {
+            let (public_key, _) = DH_KEM_X25519_HKDF_SHA256_AES_128
+                .generate_key_pair()
+                .unwrap();
+            build_config_list(&public_key.0, "public.test").0
+        };
+        let (first, second) = (key(), key());
+        *record.lock().unwrap() = (first.clone(), 0);
+
+        let resolver = Resolver::new(&crate::ResolverCli {
+            doh_url: Some(url),
+            resolve: Vec::new(),
+            ip_version: crate::IpVersion::Any,
+            happy_eyeballs_delay_ms: None,
+            connect_timeout_ms: None,
+        })
+        .unwrap();
+        let builds = Arc::new(AtomicUsize::new(0));
+        let rotation = {
+            let builds = builds.clone();
+            EchRotation::new(
+                Some(Ech::Dns),
+                resolver,
+                "secret.test".to_owned(),
+                move |ech| {
+                    assert!(ech.is_some());
+                    Ok(builds.fetch_add(1, Ordering::SeqCst) + 1)
+                },
+            )
+            .await
+            .unwrap()
+        };
+
+        // looked up again, but the config is the same
+        assert_eq!(rotation.current().await.unwrap(), 1);
+
+        *record.lock().unwrap() = (second, 300);
+        assert_eq!(rotation.current().await.unwrap(), 2);
+
+        // the second config is cached for its TTL
+        *record.lock().unwrap() = (first, 0);
+        assert_eq!(rotation.current().await.unwrap(), 2);
+        assert_eq!(builds.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Error};
+use md5::{Digest, Md5};
+use sha2::Sha256;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+use crate::client_hello::{self, is_grease, ClientHello, EXT_ALPN, EXT_SERVER_NAME};
+use crate::FingerprintCli;
+
+pub async fn main(args: FingerprintCli) -> Result<(), Error> {
+    let reference = match args.reference {
+        Some(ref path) => {
+            let profile = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read reference profile {}", path))?;
+            Some(parse_profile(&profile))
+        }
+        None => None,
+    };
+
+    let addr = format!("{}:{}", args.common.host, args.common.port);
+    tracing::info!(
+        "listening on {}, point a dialer at it to print its ClientHello",
+        addr
+    );
+
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    // only the first client is saved, it is taken by the first connection that gets that far
+    let save = Arc::new(Mutex::new(args.save));
+
+    loop {
+        let (socket, peer) = listener.accept().await.unwrap();
+        let reference = reference.clone();
+        let save = save.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = process_connection(socket, reference, save).await {
+                tracing::warn!("failed to fingerprint {}: {:?}", peer, e);
+            }
+        });
+    }
+}
+
+async fn process_connection(
+    mut socket: TcpStream,
+    reference: Option<HashMap<String, String>>,
+    save: Arc<Mutex<Option<String>>>,
+) -> Result<(), Error> {
+    let mut buf = Vec::new();
+
+    // the connection is dropped after the ClientHello, so the client will always fail the
+    // handshake
+    let hello = loop {
+        if let Some(record_len) = client_hello::record_len(&buf) {
+            if buf.len() >= record_len? {
+                break ClientHello::parse(&buf)?;
+            }
+        }
+
+        let mut chunk = [0u8; 4096];
+        let read = socket
+            .read(&mut chunk)
+            .await
+            .context("failed to read from client")?;
+        anyhow::ensure!(
+            read > 0,
+            "connection closed before ClientHello was complete"
+        );
+        buf.extend_from_slice(&chunk[..read]);
+    };
+
+    let profile = fingerprint(&hello, &buf);
+    let formatted = format_profile(&profile);
+
+    {
+        let mut stdout = std::io::stdout().lock();
+        writeln!(stdout, "{}", formatted)?;
+
+        if let Some(reference) = reference {
+            let mut mismatches = 0;
+            for (key, value) in &profile {
+                if let Some(expected) = reference.get(*key) {
+                    if expected != value {
+                        writeln!(
+                            stdout,
+                            "MISMATCH {}:\n  expected: {}\n  actual:   {}",
+                            key, expected, value
+                        )?;
+                        mismatches += 1;
+                    }
+                }
+            }
+
+            if mismatches == 0 {
+                writeln!(stdout, "matches reference profile")?;
+            }
+            writeln!(stdout)?;
+        }
+    }
+
+    let path = save.lock().unwrap().take();
+    if let Some(path) = path {
+        tokio::fs::write(&path, formatted)
+            .await
+            .with_context(|| format!("failed to save profile to {}", path))?;
+        tracing::info!("saved profile to {}", path);
+    }
+
+    Ok(())
+}
+
+/// All properties of a ClientHello, as (name, value) pairs in a stable order.
+fn fingerprint(hello: &ClientHello, buf: &[u8]) -> Vec<(&'static str, String)> {
+    let alpn = hello
+        .alpn
+        .iter()
+        .map(|protocol| String::from_utf8_lossy(protocol).into_owned())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let ja3 = ja3(hello);
+    let ja3_hash = format!("{:x}", Md5::digest(ja3.as_bytes()));
+
+    vec![
+        ("sni", hello.sni(buf).unwrap_or_default().to_owned()),
+        ("alpn", alpn),
+        ("version", hello.version.to_string()),
+        ("supported_versions", join_dec(&hello.supported_versions)),
+        ("ciphers", join_dec(&hello.cipher_suites)),
+        ("extensions", join_dec(&hello.extensions)),
+        ("curves", join_dec(&hello.supported_groups)),
+        ("point_formats", join_dec(&hello.point_formats)),
+        (
+            "signature_algorithms",
+            join_dec(&hello.signature_algorithms),
+        ),
+        ("ja3", ja3),
+        ("ja3_hash", ja3_hash),
+        ("ja4", ja4(hello)),
+    ]
+}
+
+fn join_dec<T: ToString>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn without_grease(values: &[u16]) -> Vec<u16> {
+    values.iter().copied().filter(|v| !is_grease(*v)).collect()
+}
+
+/// https://github.com/salesforce/ja3
+fn ja3(hello: &ClientHello) -> String {
+    format!(
+        "{},{},{},{},{}",
+        hello.version,
+        join_dec(&without_grease(&hello.cipher_suites)),
+        join_dec(&without_grease(&hello.extensions)),
+        join_dec(&without_grease(&hello.supported_groups)),
+        join_dec(&hello.point_formats),
+    )
+}
+
+/// https://github.com/FoxIO-LLC/ja4/blob/main/technical_details/JA4.md
+fn ja4(hello: &ClientHello) -> String {
+    let version = without_grease(&hello.supported_versions)
+        .into_iter()
+        .max()
+        .unwrap_or(hello.version);
+    let version = match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        _ => "00",
+    };
+
+    let sni = if hello.extensions.contains(&EXT_SERVER_NAME) {
+        'd'
+    } else {
+        'i'
+    };
+
+    let ciphers = without_grease(&hello.cipher_suites);
+    let extensions = without_grease(&hello.extensions);
+
+    let alpn = match hello.alpn.first() {
+        Some(protocol) if !protocol.is_empty() => {
+            let first = protocol[0];
+            let last = protocol[protocol.len() - 1];
+            if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                format!("{}{}", first as char, last as char)
+            } else {
+                let hex = format!("{:02x}{:02x}", first, last);
+                format!("{}{}", &hex[..1], &hex[3..])
+            }
+        }
+        _ => "00".to_owned(),
+    };
+
+    let mut sorted_ciphers = ciphers.clone();
+    sorted_ciphers.sort();
+
+    let mut sorted_extensions = extensions
+        .iter()
+        .copied()
+        .filter(|ext| *ext != EXT_SERVER_NAME && *ext != EXT_ALPN)
+        .collect::<Vec<_>>();
+    sorted_extensions.sort();
+
+    let mut extension_string = join_hex(&sorted_extensions);
+    let signature_algorithms = without_grease(&hello.signature_algorithms);
+    if !signature_algorithms.is_empty() {
+        extension_string.push('_');
+        extension_string.push_str(&join_hex(&signature_algorithms));
+    }
+
+    format!(
+        "t{}{}{:02}{:02}{}_{}_{}",
+        version,
+        sni,
+        ciphers.len().min(99),
+        extensions.len().min(99),
+        alpn,
+        truncated_sha256(&join_hex(&sorted_ciphers), sorted_ciphers.is_empty()),
+        truncated_sha256(&extension_string, sorted_extensions.is_empty()),
+    )
+}
+
+fn join_hex(values: &[u16]) -> String {
+    values
+        .iter()
+        .map(|v| format!("{:04x}", v))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn truncated_sha256(input: &str, empty: bool) -> String {
+    if empty {
+        return "000000000000".to_owned();
+    }
+
+    let hash = format!("{:x}", Sha256::digest(input.as_bytes()));
+    hash[..12].to_owned()
+}
+
+fn format_profile(profile: &[(&'static str, String)]) -> String {
+    profile
+        .iter()
+        .map(|(key, value)| format!("{}: {}", key, value))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Parse a profile as written by --save. Lines can be removed from the file to exclude them
+/// from the comparison.
+fn parse_profile(profile: &str) -> HashMap<String, String> {
+    profile
+        .lines()
+        .filter_map(|line| line.split_once(": "))
+        .map(|(key, value)| (key.trim().to_owned(), value.trim().to_owned()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_hello::tests::build_client_hello;
+
+    #[test]
+    fn test_ja3() {
+        let record = build_client_hello(Some("www.example.com"), &["h2", "http/1.1"]);
+        let hello = ClientHello::parse(&record).unwrap();
+
+        assert_eq!(ja3(&hello), "771,4865-4866-49199,0-10-11-13-16-43,29-23,0");
+    }
+
+    #[test]
+    fn test_ja4() {
+        let record = build_client_hello(Some("www.example.com"), &["h2", "http/1.1"]);
+        let hello = ClientHello::parse(&record).unwrap();
+        assert_eq!(ja4(&hello), "t13d0306h2_40b44b994229_fb71836bce29");
+
+        let record = build_client_hello(None, &[]);
+        let hello = ClientHello::parse(&record).unwrap();
+        assert!(ja4(&hello).starts_with("t13i030400_"));
+    }
+
+    #[test]
+    fn test_profile_roundtrip() {
+        let record = build_client_hello(Some("www.example.com"), &["h2"]);
+        let hello = ClientHello::parse(&record).unwrap();
+        let profile = fingerprint(&hello, &record);
+        let parsed = parse_profile(&format_profile(&profile));
+
+        assert_eq!(parsed.len(), profile.len());
+        assert_eq!(parsed["sni"], "www.example.com");
+        assert_eq!(parsed["alpn"], "h2");
+    }
+}
@@ -0,0 +1,254 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use anyhow::{Context, Error};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::{EchMode, WebPkiServerVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use sha2::{Digest, Sha256};
+
+/// A public key pin from --pin, in curl's format: sha256//BASE64, optionally prefixed with the
+/// host it applies to, like example.com=sha256//BASE64.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pin {
+    host: Option<String>,
+    spki_sha256: [u8; 32],
+}
+
+impl Pin {
+    pub fn parse(value: &str) -> Result<Pin, Error> {
+        let (host, pin) = match value.split_once('=') {
+            // '=' is also base64 padding, which can only appear after sha256//
+            Some((host, pin)) if !host.contains("//") => (Some(host.to_owned()), pin),
+            _ => (None, value),
+        };
+
+        let hash = pin
+            .strip_prefix("sha256//")
+            .with_context(|| format!("invalid pin {:?}, expected sha256//BASE64", value))?;
+        let spki_sha256 = BASE64_STANDARD
+            .decode(hash)
+            .ok()
+            .and_then(|hash| hash.try_into().ok())
+            .with_context(|| format!("invalid pin {:?}, expected a base64 SHA-256 hash", value))?;
+
+        Ok(Pin { host, spki_sha256 })
+    }
+
+    fn applies_to(&self, host: &str) -> bool {
+        self.host
+            .as_deref()
+            .is_none_or(|pin_host| pin_host.eq_ignore_ascii_case(host))
+    }
+
+    /// The pin in curl's format, without the host.
+    #[cfg(feature = "curl")]
+    fn to_curl(&self) -> String {
+        format!("sha256//{}", BASE64_STANDARD.encode(self.spki_sha256))
+    }
+}
+
+/// Value for CURLOPT_PINNEDPUBLICKEY, or None if no pin applies to this host.
+#[cfg(feature = "curl")]
+pub fn curl_pinned_public_key(pins: &[Pin], host: &str) -> Option<String> {
+    let pins = pins
+        .iter()
+        .filter(|pin| pin.applies_to(host))
+        .map(Pin::to_curl)
+        .collect::<Vec<_>>();
+
+    if pins.is_empty() {
+        None
+    } else {
+        Some(pins.join(";"))
+    }
+}
+
+/// Check the certificate of host against all pins that apply to it. Any matching pin is enough,
+/// so that keys can be rotated.
+fn check_pins(pins: &[Pin], host: &str, end_entity: &CertificateDer) -> Result<(), Error> {
+    let pins = pins
+        .iter()
+        .filter(|pin| pin.applies_to(host))
+        .collect::<Vec<_>>();
+    if pins.is_empty() {
+        return Ok(());
+    }
+
+    let cert = webpki::EndEntityCert::try_from(end_entity)
+        .map_err(|e| anyhow::anyhow!("failed to parse certificate: {:?}", e))?;
+    let spki_sha256: [u8; 32] = Sha256::digest(cert.subject_public_key_info()).into();
+
+    anyhow::ensure!(
+        pins.iter().any(|pin| pin.spki_sha256 == spki_sha256),
+        "public key of {} is sha256//{}, which does not match any --pin",
+        host,
+        BASE64_STANDARD.encode(spki_sha256)
+    );
+
+    Ok(())
+}
+
+/// A rustls client config that verifies certificates as usual, and additionally checks pins and
+/// encrypts the ClientHello if ech is given.
+pub fn rustls_config(pins: Vec<Pin>, ech: Option<EchMode>) -> Result<rustls::ClientConfig, Error> {
+    // reqwest enables ring, but ECH needs aws-lc-rs for HPKE, so there is no unambiguous default
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let verifier = server_verifier(pins, false, provider.clone());
+
+    let builder = rustls::ClientConfig::builder_with_provider(provider);
+    let builder = match ech {
+        // ECH is TLS 1.3 only
+        Some(ech) => builder.with_ech(ech)?,
+        None => builder.with_safe_default_protocol_versions()?,
+    };
+    let mut config = builder
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    // same as reqwest's own config without http2
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// A certificate verifier that checks pins after verifying the certificate against the webpki
+/// roots. With insecure, only the pins are checked, like curl's --insecure with --pinnedpubkey.
+pub fn server_verifier(
+    pins: Vec<Pin>,
+    insecure: bool,
+    provider: Arc<CryptoProvider>,
+) -> Arc<dyn ServerCertVerifier> {
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    Arc::new(PinnedServerVerifier {
+        inner: WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider)
+            .build()
+            .unwrap(),
+        pins,
+        insecure,
+    })
+}
+
+#[derive(Debug)]
+struct PinnedServerVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<Pin>,
+    insecure: bool,
+}
+
+impl ServerCertVerifier for PinnedServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if !self.insecure {
+            self.inner.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            )?;
+        }
+
+        let host = match server_name {
+            ServerName::DnsName(name) => name.as_ref().to_owned(),
+            ServerName::IpAddress(ip) => IpAddr::from(*ip).to_string(),
+            _ => String::new(),
+        };
+
+        if let Err(e) = check_pins(&self.pins, &host, end_entity) {
+            tracing::warn!("refusing connection: {}", e);
+            return Err(rustls::Error::General(e.to_string()));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // self-signed P-256 certificate for pin.test
+    const CERT: &str = "MIIBezCCASGgAwIBAgIUPVnZ9NM1fdw5T85yOUa7zg5iTIAwCgYIKoZIzj0EAwIwEzERMA8GA1UEAwwIcGluLnRlc3QwHhcNMjYxMDE4MTc1MjI4WhcNMzYxMDE1MTc1MjI4WjATMREwDwYDVQQDDAhwaW4udGVzdDBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABF9jJjtnc7zjNAGFpMpYgafErqjWlY7q/O+rJdmjgKe4Br/C/XYtqS4syNHHUdofTlOASKvjxuAs0mmJqVEnEMyjUzBRMB0GA1UdDgQWBBTiQIYRPFTMj/fog9f1SoPCAmfywTAfBgNVHSMEGDAWgBTiQIYRPFTMj/fog9f1SoPCAmfywTAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0gAMEUCIQDs2uG4pg0ofRnqVHPSd6AeJIrDr9akr6s2E3bBS1a7qQIgQO6dyvDCY3HLAy9gNeirbRPKdaUSjFKHj8au53Z3d9I=";
+    // openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64
+    const PIN: &str = "sha256//z638irNjGDU8hQoi5LmhxEnOskMRClB82Zhbcjt1PNM=";
+    const OTHER_PIN: &str = "sha256//AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+
+    #[test]
+    fn test_parse() {
+        let pin = Pin::parse(PIN).unwrap();
+        assert_eq!(pin.host, None);
+        #[cfg(feature = "curl")]
+        assert_eq!(pin.to_curl(), PIN);
+
+        let pin = Pin::parse(&format!("example.com={}", PIN)).unwrap();
+        assert_eq!(pin.host.as_deref(), Some("example.com"));
+        assert!(pin.applies_to("EXAMPLE.com"));
+        assert!(!pin.applies_to("example.org"));
+
+        assert!(Pin::parse("z638irNjGDU8hQoi5LmhxEnOskMRClB82Zhbcjt1PNM=").is_err());
+        assert!(Pin::parse("sha256//dG9vIHNob3J0").is_err());
+    }
+
+    #[cfg(feature = "curl")]
+    #[test]
+    fn test_curl_pinned_public_key() {
+        let pins = [
+            Pin::parse(PIN).unwrap(),
+            Pin::parse(&format!("example.org={}", OTHER_PIN)).unwrap(),
+        ];
+
+        assert_eq!(
+            curl_pinned_public_key(&pins, "example.org").unwrap(),
+            format!("{};{}", PIN, OTHER_PIN)
+        );
+        assert_eq!(curl_pinned_public_key(&pins, "example.com").unwrap(), PIN);
+        assert_eq!(curl_pinned_public_key(&pins[1..], "example.com"), None);
+    }
+
+    #[test]
+    fn test_check_pins() {
+        let cert = CertificateDer::from(BASE64_STANDARD.decode(CERT).unwrap());
+
+        let matching = [Pin::parse(OTHER_PIN).unwrap(), Pin::parse(PIN).unwrap()];
+        check_pins(&matching, "pin.test", &cert).unwrap();
+
+        let mismatching = [Pin::parse(&format!("pin.test={}", OTHER_PIN)).unwrap()];
+        let err = check_pins(&mismatching, "pin.test", &cert).unwrap_err();
+        assert!(err.to_string().contains(PIN));
+        // the pin is for another host
+        check_pins(&mismatching, "example.com", &cert).unwrap();
+    }
+}
@@ -0,0 +1,699 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Error};
+use axum::body::Bytes;
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+
+use crate::{IpVersion, ResolverCli};
+
+const QTYPE_A: u16 = 1;
+const QTYPE_AAAA: u16 = 28;
+const QTYPE_HTTPS: u16 = 65;
+// SvcParamKey of the ECHConfigList in HTTPS records
+const SVCPARAM_ECH: u16 = 5;
+
+/// The default of --happy-eyeballs-delay-ms.
+pub const DEFAULT_HAPPY_EYEBALLS_DELAY_MS: u64 = 250;
+
+/// DoH answers by (hostname, qtype), with their expiry time.
+type DohCache<T> = Mutex<HashMap<(String, u16), (Instant, T)>>;
+
+/// Resolves upstream hostnames and connects to them for the modes that dial out by themselves
+/// (tcp-fragment, tls, split-http and split-http-server), honoring --doh-url, --resolve,
+/// --ip-version, --happy-eyeballs-delay-ms and --connect-timeout-ms.
+///
+/// The curl dialers pass the same options to curl instead. split-http's reqwest backend only
+/// resolves with it, and has its own happy eyeballs.
+#[derive(Clone)]
+pub struct Resolver {
+    overrides: Arc<Vec<Override>>,
+    doh: Option<DohClient>,
+    ip_version: IpVersion,
+    attempt_delay: Duration,
+    connect_timeout: Option<Duration>,
+}
+
+/// One --resolve entry, in curl's format: host:port:addr[,addr]...
+#[derive(Debug, PartialEq)]
+struct Override {
+    host: String,
+    port: u16,
+    addrs: Vec<IpAddr>,
+}
+
+#[derive(Clone)]
+struct DohClient {
+    client: reqwest::Client,
+    url: String,
+    cache: Arc<DohCache<Vec<IpAddr>>>,
+    ech_cache: Arc<DohCache<Option<Vec<u8>>>>,
+}
+
+impl Resolver {
+    pub fn new(args: &ResolverCli) -> Result<Self, Error> {
+        let overrides = args
+            .resolve
+            .iter()
+            .map(|entry| parse_override(entry))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let doh = args.doh_url.as_ref().map(|url| DohClient {
+            client: reqwest::Client::new(),
+            url: url.clone(),
+            cache: Default::default(),
+            ech_cache: Default::default(),
+        });
+
+        Ok(Resolver {
+            overrides: Arc::new(overrides),
+            doh,
+            ip_version: args.ip_version,
+            attempt_delay: Duration::from_millis(
+                args.happy_eyeballs_delay_ms
+                    .unwrap_or(DEFAULT_HAPPY_EYEBALLS_DELAY_MS),
+            ),
+            connect_timeout: args.connect_timeout_ms.map(Duration::from_millis),
+        })
+    }
+
+    /// Resolve a hostname to a list of addresses with the given port.
+    ///
+    /// If port is None, --resolve entries match regardless of their port, and the returned
+    /// addresses have port 0.
+    pub async fn lookup(&self, host: &str, port: Option<u16>) -> Result<Vec<SocketAddr>, Error> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        let addrs = if let Ok(ip) = host.parse::<IpAddr>() {
+            vec![ip]
+        } else if let Some(entry) = self.overrides.iter().find(|entry| {
+            entry.host.eq_ignore_ascii_case(host) && port.is_none_or(|port| port == entry.port)
+        }) {
+            entry.addrs.clone()
+        } else if let Some(ref doh) = self.doh {
+            // a server that fails the query for one family must not break the other one
+            match tokio::join!(doh.query(host, QTYPE_AAAA), doh.query(host, QTYPE_A)) {
+                (Err(e), Err(_)) => return Err(e),
+                (ipv6, ipv4) => [ipv6, ipv4]
+                    .into_iter()
+                    .flat_map(|result| {
+                        result.unwrap_or_else(|e| {
+                            tracing::debug!("{:#}", e);
+                            Vec::new()
+                        })
+                    })
+                    .collect(),
+            }
+        } else {
+            tokio::net::lookup_host((host, 0))
+                .await
+                .with_context(|| format!("failed to resolve {}", host))?
+                .map(|addr| addr.ip())
+                .collect()
+        };
+
+        let mut addrs = addrs
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port.unwrap_or(0)))
+            .collect::<Vec<_>>();
+        // the preferred family first, so that happy eyeballs starts with it
+        addrs.sort_by_key(|addr| match self.ip_version {
+            IpVersion::Any => false,
+            IpVersion::V4 => !addr.is_ipv4(),
+            IpVersion::V6 => !addr.is_ipv6(),
+        });
+
+        anyhow::ensure!(!addrs.is_empty(), "no addresses for {}", host);
+        Ok(addrs)
+    }
+
+    /// Look up the ECHConfigList in the HTTPS record of a hostname. Only possible with --doh-url,
+    /// as the system resolver can only resolve addresses. Like addresses, the config is cached
+    /// for the TTL of the record.
+    pub async fn lookup_ech_config(&self, host: &str) -> Result<Vec<u8>, Error> {
+        let doh = self
+            .doh
+            .as_ref()
+            .context("looking up the ECH config requires --doh-url, or use --ech-config")?;
+        doh.cached(&doh.ech_cache, host, QTYPE_HTTPS, |response| {
+            let (config, ttl) = parse_https_response(response)?;
+            if config.is_some() {
+                tracing::debug!("found ECH config for {} using DoH", host);
+            }
+            Ok((config, ttl))
+        })
+        .await?
+        .with_context(|| format!("{} has no HTTPS record with an ECH config", host))
+    }
+
+    /// Open a TCP connection to an upstream in host:port format, trying its addresses with happy
+    /// eyeballs.
+    pub async fn connect(&self, upstream: &str) -> Result<TcpStream, Error> {
+        let (host, port) = upstream
+            .rsplit_once(':')
+            .with_context(|| format!("port missing in {}", upstream))?;
+        let port = port
+            .parse()
+            .with_context(|| format!("invalid port in {}", upstream))?;
+
+        let addrs = self.lookup(host, Some(port)).await?;
+        let attempts = happy_eyeballs(addrs, self.attempt_delay);
+        let result = match self.connect_timeout {
+            Some(connect_timeout) => timeout(connect_timeout, attempts)
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+            None => attempts.await,
+        };
+
+        result.with_context(|| format!("failed to connect to {}", upstream))
+    }
+}
+
+/// Connect to the first of the addresses that accepts, as in RFC 8305: the addresses are tried
+/// in turn, alternating between address families, and the next attempt starts as soon as the
+/// previous one has failed or has not succeeded within attempt_delay. Earlier attempts keep
+/// running. Returns the last error if all of them fail.
+async fn happy_eyeballs(addrs: Vec<SocketAddr>, attempt_delay: Duration) -> io::Result<TcpStream> {
+    let mut addrs = interleave_families(addrs).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+
+    loop {
+        if let Some(addr) = addrs.next() {
+            tracing::debug!("connecting to {}", addr);
+            attempts.push(async move { (addr, TcpStream::connect(addr).await) });
+        }
+        if attempts.is_empty() {
+            return Err(last_err.unwrap_or_else(|| io::ErrorKind::NotFound.into()));
+        }
+
+        // wait for the current attempts until one fails or it is time for the next one
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    tracing::debug!("failed to connect to {}: {}", addr, e);
+                    last_err = Some(e);
+                }
+            },
+            _ = sleep(attempt_delay), if addrs.len() > 0 => {}
+        }
+    }
+}
+
+/// Reorder addresses to alternate between IPv6 and IPv4, starting with the family of the first
+/// one and otherwise keeping their order.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_ipv6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_ipv6);
+
+    let mut interleaved = Vec::with_capacity(first.len() + second.len());
+    while !first.is_empty() || !second.is_empty() {
+        interleaved.extend(first.pop_front());
+        interleaved.extend(second.pop_front());
+    }
+    interleaved
+}
+
+impl reqwest::dns::Resolve for Resolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let resolver = self.clone();
+        Box::pin(async move {
+            // the port is filled in by reqwest
+            let addrs = resolver.lookup(name.as_str(), None).await?;
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+impl DohClient {
+    async fn query(&self, host: &str, qtype: u16) -> Result<Vec<IpAddr>, Error> {
+        self.cached(&self.cache, host, qtype, |response| {
+            let (addrs, ttl) = parse_response(response)?;
+            tracing::debug!("resolved {} to {:?} using DoH", host, addrs);
+            Ok((addrs, ttl))
+        })
+        .await
+    }
+
+    /// Return the cached answer of a query, or send it and cache what parse extracts from the
+    /// response for as long as it says.
+    async fn cached<T: Clone>(
+        &self,
+        cache: &DohCache<T>,
+        host: &str,
+        qtype: u16,
+        parse: impl FnOnce(&[u8]) -> Result<(T, Duration), Error>,
+    ) -> Result<T, Error> {
+        let key = (host.to_ascii_lowercase(), qtype);
+        if let Some((expires, answer)) = cache.lock().unwrap().get(&key) {
+            if *expires > Instant::now() {
+                return Ok(answer.clone());
+            }
+        }
+
+        let response = self.exchange(host, qtype).await?;
+        let (answer, ttl) =
+            parse(&response).with_context(|| format!("invalid DoH response for {}", host))?;
+
+        let now = Instant::now();
+        let mut cache = cache.lock().unwrap();
+        // every hostname ever looked up would stay in the cache otherwise
+        cache.retain(|_, (expires, _)| *expires > now);
+        cache.insert(key, (now + ttl, answer.clone()));
+        Ok(answer)
+    }
+
+    /// Send a query and return the raw response message.
+    async fn exchange(&self, host: &str, qtype: u16) -> Result<Bytes, Error> {
+        // RFC 8484, POST avoids having to base64-encode the query
+        self.client
+            .post(&self.url)
+            .header("Content-Type", "application/dns-message")
+            .header("Accept", "application/dns-message")
+            .body(build_query(host, qtype)?)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("DoH query for {} failed", host))?
+            .bytes()
+            .await
+            .with_context(|| format!("DoH query for {} failed", host))
+    }
+}
+
+fn parse_override(entry: &str) -> Result<Override, Error> {
+    let invalid = || {
+        format!(
+            "invalid --resolve entry {:?}, expected host:port:addr",
+            entry
+        )
+    };
+
+    let (host, rest) = entry.split_once(':').with_context(invalid)?;
+    let (port, addrs) = rest.split_once(':').with_context(invalid)?;
+    let port = port.parse().with_context(invalid)?;
+    let addrs = addrs
+        .split(',')
+        .map(|addr| {
+            addr.trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(invalid)?;
+
+    Ok(Override {
+        host: host.to_owned(),
+        port,
+        addrs,
+    })
+}
+
+/// Build a DNS query message with recursion desired.
+fn build_query(host: &str, qtype: u16) -> Result<Vec<u8>, Error> {
+    // id 0 is recommended for DoH, flags: RD, one question
+    let mut msg = vec![0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+
+    for label in host.trim_end_matches('.').split('.') {
+        anyhow::ensure!(
+            !label.is_empty() && label.len() < 64,
+            "invalid hostname {}",
+            host
+        );
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    msg.extend_from_slice(&qtype.to_be_bytes());
+    // class IN
+    msg.extend_from_slice(&1u16.to_be_bytes());
+
+    Ok(msg)
+}
+
+/// Extract all A and AAAA records from a DNS response, and how long they may be cached.
+fn parse_response(msg: &[u8]) -> Result<(Vec<IpAddr>, Duration), Error> {
+    let mut addrs = Vec::new();
+    let mut ttl = u32::MAX;
+
+    for (rtype, record_ttl, rdata) in parse_answers(msg)? {
+        let addr = match (rtype, rdata.len()) {
+            (QTYPE_A, 4) => IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])),
+            (QTYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            // CNAMEs are followed by the server, their targets are part of the answer
+            _ => continue,
+        };
+
+        addrs.push(addr);
+        ttl = ttl.min(record_ttl);
+    }
+
+    if addrs.is_empty() {
+        ttl = 0;
+    }
+
+    Ok((addrs, Duration::from_secs(ttl.into())))
+}
+
+/// Extract the ECHConfigList from the first HTTPS record of a DNS response that has one, and
+/// how long it may be cached.
+fn parse_https_response(msg: &[u8]) -> Result<(Option<Vec<u8>>, Duration), Error> {
+    for (rtype, ttl, rdata) in parse_answers(msg)? {
+        if rtype != QTYPE_HTTPS {
+            continue;
+        }
+
+        // RFC 9460: priority, target name (never compressed), then the SvcParams
+        let mut pos = skip_name(rdata, 2)?;
+        while pos < rdata.len() {
+            let param = rdata.get(pos..pos + 4).context("HTTPS record truncated")?;
+            let key = u16::from_be_bytes([param[0], param[1]]);
+            let len = u16::from_be_bytes([param[2], param[3]]) as usize;
+            let value = rdata
+                .get(pos + 4..pos + 4 + len)
+                .context("HTTPS record truncated")?;
+            if key == SVCPARAM_ECH {
+                return Ok((Some(value.to_vec()), Duration::from_secs(ttl.into())));
+            }
+            pos += 4 + len;
+        }
+    }
+
+    Ok((None, Duration::ZERO))
+}
+
+/// Type, TTL and data of an answer record.
+type Answer<'a> = (u16, u32, &'a [u8]);
+
+/// Return all answer records in a DNS response.
+fn parse_answers(msg: &[u8]) -> Result<Vec<Answer<'_>>, Error> {
+    let u16_at = |pos: usize| -> Result<u16, Error> {
+        msg.get(pos..pos + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .context("message truncated")
+    };
+
+    let flags = u16_at(2)?;
+    let rcode = flags & 0xf;
+    anyhow::ensure!(rcode == 0, "server returned rcode {}", rcode);
+
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+    let mut pos = 12;
+
+    for _ in 0..questions {
+        pos = skip_name(msg, pos)? + 4;
+    }
+
+    let mut records = Vec::new();
+
+    for _ in 0..answers {
+        pos = skip_name(msg, pos)?;
+        let rtype = u16_at(pos)?;
+        let ttl = msg
+            .get(pos + 4..pos + 8)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .context("message truncated")?;
+        let rdlen = u16_at(pos + 8)? as usize;
+        pos += 10;
+        let rdata = msg.get(pos..pos + rdlen).context("message truncated")?;
+        pos += rdlen;
+
+        records.push((rtype, ttl, rdata));
+    }
+
+    Ok(records)
+}
+
+/// Return the position right after the (possibly compressed) name at pos.
+fn skip_name(msg: &[u8], mut pos: usize) -> Result<usize, Error> {
+    loop {
+        let len = *msg.get(pos).context("message truncated")?;
+        if len == 0 {
+            return Ok(pos + 1);
+        } else if len & 0xc0 == 0xc0 {
+            // compression pointer, ends the name
+            return Ok(pos + 2);
+        }
+        pos += 1 + len as usize;
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_override() {
+        assert_eq!(
+            parse_override("example.com:443:127.0.0.1,[::1]").unwrap(),
+            Override {
+                host: "example.com".to_owned(),
+                port: 443,
+                addrs: vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()],
+            }
+        );
+
+        assert!(parse_override("example.com:443").is_err());
+        assert!(parse_override("example.com:https:127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn test_build_query() {
+        let query = build_query("example.com", QTYPE_A).unwrap();
+        assert_eq!(
+            query,
+            b"\x00\x00\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07example\x03com\x00\x00\x01\x00\x01"
+        );
+
+        assert!(build_query("example..com", QTYPE_A).is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let mut response = build_query("example.com", QTYPE_A).unwrap();
+        // QR, RD, RA, two answers
+        response[2..4].copy_from_slice(&[0x81, 0x80]);
+        response[6..8].copy_from_slice(&[0, 2]);
+
+        // CNAME, compressed name pointing to the question
+        response.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 12]);
+        // A 93.184.216.34, TTL 30
+        response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 30, 0, 4, 93, 184, 216, 34]);
+
+        let (addrs, ttl) = parse_response(&response).unwrap();
+        assert_eq!(addrs, vec![IpAddr::from([93, 184, 216, 34])]);
+        assert_eq!(ttl, Duration::from_secs(30));
+
+        // NXDOMAIN
+        response[3] = 0x83;
+        assert!(parse_response(&response).is_err());
+
+        assert!(parse_response(&response[..20]).is_err());
+    }
+
+    #[test]
+    fn test_parse_https_response() {
+        let response = |params: &[u8]| {
+            let mut response = build_query("example.com", QTYPE_HTTPS).unwrap();
+            response[2..4].copy_from_slice(&[0x81, 0x80]);
+            response[6..8].copy_from_slice(&[0, 1]);
+            // priority 1, target name "."
+            let rdata = [&[0, 1, 0][..], params].concat();
+            response.extend_from_slice(&[0xc0, 12, 0, 65, 0, 1, 0, 0, 1, 44, 0]);
+            response.push(rdata.len() as u8);
+            response.extend_from_slice(&rdata);
+            response
+        };
+
+        // alpn=h2 ech=AQID
+        let params = [0, 1, 0, 3, 2, b'h', b'2', 0, 5, 0, 3, 1, 2, 3];
+        assert_eq!(
+            parse_https_response(&response(&params)).unwrap(),
+            (Some(vec![1, 2, 3]), Duration::from_secs(300))
+        );
+        assert_eq!(
+            parse_https_response(&response(&params[..7])).unwrap(),
+            (None, Duration::ZERO)
+        );
+        assert!(parse_https_response(&response(&params[..13])).is_err());
+    }
+
+    /// A listener whose accept queue is full, so that connecting to it hangs. Returns the
+    /// address and everything that has to be kept alive.
+    pub async fn hanging_listener() -> (SocketAddr, tokio::net::TcpListener, Vec<TcpStream>) {
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(0).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut queued = Vec::new();
+        for _ in 0..10 {
+            match timeout(Duration::from_millis(100), TcpStream::connect(addr)).await {
+                Ok(stream) => queued.push(stream.unwrap()),
+                Err(_) => return (addr, listener, queued),
+            }
+        }
+        panic!("accept queue never filled up");
+    }
+
+    #[test]
+    fn test_interleave_families() {
+        let addrs = ["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect::<Vec<SocketAddr>>();
+        let interleaved = interleave_families(addrs.clone())
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            interleaved,
+            ["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"]
+        );
+
+        let interleaved = interleave_families(vec![addrs[3], addrs[0], addrs[4]]);
+        assert_eq!(interleaved, vec![addrs[3], addrs[0], addrs[4]]);
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let good = listener.local_addr().unwrap();
+        // nothing listens there anymore
+        let refused = {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+        let (hanging, _listener, _queued) = hanging_listener().await;
+
+        // a refused connection moves on right away
+        let start = Instant::now();
+        let stream = happy_eyeballs(vec![refused, good], Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), good);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // a hanging one after the attempt delay
+        let start = Instant::now();
+        let stream = happy_eyeballs(vec![hanging, good], Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), good);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        assert_eq!(
+            happy_eyeballs(vec![refused], Duration::from_millis(50))
+                .await
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout() {
+        let (hanging, _listener, _queued) = hanging_listener().await;
+        let resolver = Resolver::new(&ResolverCli {
+            doh_url: None,
+            resolve: Vec::new(),
+            ip_version: IpVersion::Any,
+            happy_eyeballs_delay_ms: None,
+            connect_timeout_ms: Some(100),
+        })
+        .unwrap();
+
+        let err = resolver.connect(&hanging.to_string()).await.unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            format!("failed to connect to {}: timed out", hanging)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup_override() {
+        let resolver = Resolver::new(&ResolverCli {
+            doh_url: None,
+            resolve: vec!["example.com:443:10.0.0.1,::1".to_owned()],
+            ip_version: IpVersion::V4,
+            happy_eyeballs_delay_ms: None,
+            connect_timeout_ms: None,
+        })
+        .unwrap();
+
+        // IPv6 is still used, but after IPv4
+        assert_eq!(
+            resolver.lookup("example.com", Some(443)).await.unwrap(),
+            vec![
+                "10.0.0.1:443".parse().unwrap(),
+                "[::1]:443".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            resolver.lookup("[::1]", Some(80)).await.unwrap(),
+            vec!["[::1]:80".parse().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup_doh_failed_family() {
+        // a DoH server that answers A queries with 127.0.0.1 and fails all others
+        async fn doh(query: Bytes) -> Result<Vec<u8>, axum::http::StatusCode> {
+            let qtype = u16::from_be_bytes([query[query.len() - 4], query[query.len() - 3]]);
+            if qtype != QTYPE_A {
+                return Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+
+            let mut response = query.to_vec();
+            response[2..4].copy_from_slice(&[0x81, 0x80]);
+            response[6..8].copy_from_slice(&[0, 1]);
+            response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 30, 0, 4, 127, 0, 0, 1]);
+            Ok(response)
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/dns-query", listener.local_addr().unwrap());
+        let app = axum::Router::new().route("/dns-query", axum::routing::post(doh));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let resolver = Resolver::new(&ResolverCli {
+            doh_url: Some(url),
+            resolve: Vec::new(),
+            ip_version: IpVersion::V6,
+            happy_eyeballs_delay_ms: None,
+            connect_timeout_ms: None,
+        })
+        .unwrap();
+
+        assert_eq!(
+            resolver.lookup("example.com", Some(443)).await.unwrap(),
+            vec!["127.0.0.1:443".parse().unwrap()]
+        );
+
+        // expired answers are evicted when new ones come in
+        let cache = resolver.doh.as_ref().unwrap().cache.clone();
+        let expired = ("expired.example.com".to_owned(), QTYPE_A);
+        cache
+            .lock()
+            .unwrap()
+            .insert(expired.clone(), (Instant::now(), Vec::new()));
+        resolver.lookup("example.org", None).await.unwrap();
+        let cache = cache.lock().unwrap();
+        assert!(!cache.contains_key(&expired));
+        assert!(cache.contains_key(&("example.com".to_owned(), QTYPE_A)));
+    }
+}
@@ -1,2 +1,85 @@
+use std::str::FromStr;
+
+use anyhow::Error;
+use rand::Rng;
+
 pub mod client;
 pub mod server;
+
+/// The query parameter and cookie name that carry the padding. The query parameter is also what
+/// tells new clients apart from old ones, see
+/// https://github.com/XTLS/Xray-core/blob/6baad79f9881ee2cf75bdc825b3e2e92b289477a/transport/internet/splithttp/hub.go#L199
+const PADDING_PARAM: &str = "x_padding";
+
+/// The header that carries the padding with --padding-location header.
+const PADDING_HEADER: &str = "x-padding";
+
+/// How many bytes of padding to send, or to accept, from --padding-bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PaddingRange {
+    min: usize,
+    max: usize,
+}
+
+impl PaddingRange {
+    /// A random padding of a length in the range.
+    fn sample(&self) -> String {
+        "X".repeat(rand::thread_rng().gen_range(self.min..=self.max))
+    }
+
+    fn contains(&self, len: usize) -> bool {
+        (self.min..=self.max).contains(&len)
+    }
+}
+
+impl FromStr for PaddingRange {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Error> {
+        let parse = |bytes: &str| {
+            bytes
+                .parse::<usize>()
+                .map_err(|_| anyhow::anyhow!("expected BYTES or MIN-MAX, got {:?}", value))
+        };
+
+        let (min, max) = match value.split_once('-') {
+            Some((min, max)) => (parse(min)?, parse(max)?),
+            None => (parse(value)?, parse(value)?),
+        };
+        anyhow::ensure!(min <= max, "{:?}: minimum is greater than maximum", value);
+        Ok(PaddingRange { min, max })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_padding_range() {
+        assert_eq!(
+            "100-1000".parse::<PaddingRange>().unwrap(),
+            PaddingRange {
+                min: 100,
+                max: 1000
+            }
+        );
+        assert_eq!(
+            "0".parse::<PaddingRange>().unwrap(),
+            PaddingRange { min: 0, max: 0 }
+        );
+        assert!("1000-100".parse::<PaddingRange>().is_err());
+        assert!("-5".parse::<PaddingRange>().is_err());
+        assert!("many".parse::<PaddingRange>().is_err());
+    }
+
+    #[test]
+    fn test_padding_sample() {
+        let range = PaddingRange { min: 3, max: 5 };
+        for _ in 0..100 {
+            let padding = range.sample();
+            assert!(range.contains(padding.len()));
+            assert!(padding.bytes().all(|b| b == b'X'));
+        }
+    }
+}
@@ -1,9 +1,22 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use anyhow::{Context, Error};
+use axum::body::Bytes;
+use axum::http::header::COOKIE;
 use axum::http::{HeaderMap, HeaderName, HeaderValue};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+#[cfg(feature = "curl")]
+use tokio::sync::mpsc;
 
-use crate::SplitHttpCli;
+#[cfg(feature = "curl")]
+use crate::curl::http::CurlHttpClient;
+use crate::ech::{Ech, EchRotation};
+use crate::pin::{self, Pin};
+use crate::resolver::Resolver;
+use crate::splithttp::{PaddingRange, PADDING_HEADER, PADDING_PARAM};
+use crate::{PaddingLocation, SplitHttpBackend, SplitHttpCli};
 
 pub async fn main(args: SplitHttpCli) -> Result<(), Error> {
     let addr = format!("{}:{}", args.common.host, args.common.port);
@@ -17,7 +30,16 @@ pub async fn main(args: SplitHttpCli) -> Result<(), Error> {
         headermap.clone()
     };
 
-    let upstream_client = reqwest::Client::new();
+    let upstream_client = match args.backend {
+        SplitHttpBackend::Reqwest => HttpClient::Reqwest(Arc::new(reqwest_client(&args).await?)),
+        #[cfg(feature = "curl")]
+        SplitHttpBackend::Curl => HttpClient::Curl(CurlHttpClient::new(
+            &args.curl,
+            &args.resolver,
+            &args.pinning,
+            &args.ech,
+        )?),
+    };
 
     loop {
         let (socket, _) = listener.accept().await.unwrap();
@@ -45,6 +67,10 @@ pub async fn main(args: SplitHttpCli) -> Result<(), Error> {
                 download_upstream,
                 upstream,
                 args.upload_chunk_size,
+                Padding {
+                    bytes: args.padding_bytes,
+                    location: args.padding_location,
+                },
             )
             .await
             {
@@ -54,6 +80,45 @@ pub async fn main(args: SplitHttpCli) -> Result<(), Error> {
     }
 }
 
+/// The client for --backend reqwest. It resolves with the Resolver, but connects by itself.
+async fn reqwest_client(args: &SplitHttpCli) -> Result<EchRotation<reqwest::Client>, Error> {
+    // reqwest's happy eyeballs delay is fixed
+    anyhow::ensure!(
+        args.resolver.happy_eyeballs_delay_ms.is_none(),
+        "--happy-eyeballs-delay-ms is not supported by the reqwest backend, use --backend curl"
+    );
+
+    let resolver = Resolver::new(&args.resolver)?;
+    let pins = args
+        .pinning
+        .pin
+        .iter()
+        .map(|pin| Pin::parse(pin))
+        .collect::<Result<Vec<_>, _>>()?;
+    let url = reqwest::Url::parse(&args.upstream)?;
+    let host = url.host_str().context("upstream has no host")?.to_owned();
+    let connect_timeout = args.resolver.connect_timeout_ms.map(Duration::from_millis);
+
+    // there is only one TLS config for all requests, so the download upstream has to
+    // accept the same ECH config
+    EchRotation::new(
+        Ech::from_args(&args.ech)?,
+        resolver.clone(),
+        host,
+        move |ech| {
+            let mut builder = reqwest::Client::builder().dns_resolver(Arc::new(resolver.clone()));
+            if let Some(connect_timeout) = connect_timeout {
+                builder = builder.connect_timeout(connect_timeout);
+            }
+            if !pins.is_empty() || ech.is_some() {
+                builder = builder.use_preconfigured_tls(pin::rustls_config(pins.clone(), ech)?);
+            }
+            Ok(builder.build()?)
+        },
+    )
+    .await
+}
+
 fn parse_header_args(cli: &[String]) -> HeaderMap {
     let mut headermap = HeaderMap::new();
 
@@ -67,28 +132,135 @@ fn parse_header_args(cli: &[String]) -> HeaderMap {
     headermap
 }
 
+/// The padding of every request, from --padding-bytes and --padding-location.
+#[derive(Clone, Copy)]
+struct Padding {
+    bytes: PaddingRange,
+    location: PaddingLocation,
+}
+
+impl Padding {
+    /// Adds a random padding to a request, and returns the request's URL.
+    fn apply(self, url: String, headers: &mut HeaderMap) -> String {
+        let padding = self.bytes.sample();
+        match self.location {
+            PaddingLocation::Query => return format!("{url}?{PADDING_PARAM}={padding}"),
+            PaddingLocation::Header => {
+                headers.insert(
+                    HeaderName::from_static(PADDING_HEADER),
+                    HeaderValue::from_str(&padding).unwrap(),
+                );
+            }
+            PaddingLocation::Cookie => {
+                // keep the cookies from -H
+                let mut cookie = match headers.get(COOKIE) {
+                    Some(cookie) => [cookie.as_bytes(), b"; "].concat(),
+                    None => Vec::new(),
+                };
+                cookie.extend_from_slice(format!("{PADDING_PARAM}={padding}").as_bytes());
+                headers.insert(COOKIE, HeaderValue::from_bytes(&cookie).unwrap());
+            }
+        }
+
+        url
+    }
+}
+
+#[derive(Clone)]
+enum HttpClient {
+    Reqwest(Arc<EchRotation<reqwest::Client>>),
+    #[cfg(feature = "curl")]
+    Curl(CurlHttpClient),
+}
+
+impl HttpClient {
+    async fn download(&self, url: String, headers: HeaderMap) -> Result<Download, Error> {
+        match self {
+            HttpClient::Reqwest(clients) => {
+                let response = clients
+                    .current()
+                    .await?
+                    .get(url)
+                    .headers(headers)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Ok(Download::Reqwest(response))
+            }
+            #[cfg(feature = "curl")]
+            HttpClient::Curl(client) => Ok(Download::Curl(client.get(url, header_lines(&headers)))),
+        }
+    }
+
+    async fn upload(&self, url: String, headers: HeaderMap, body: Vec<u8>) -> Result<(), Error> {
+        match self {
+            HttpClient::Reqwest(clients) => {
+                let response = clients
+                    .current()
+                    .await?
+                    .post(url)
+                    .headers(headers)
+                    .body(body)
+                    .send()
+                    .await?;
+                response.error_for_status()?;
+            }
+            #[cfg(feature = "curl")]
+            HttpClient::Curl(client) => {
+                client.post(url, header_lines(&headers), body).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+enum Download {
+    Reqwest(reqwest::Response),
+    #[cfg(feature = "curl")]
+    Curl(mpsc::Receiver<Result<Bytes, Error>>),
+}
+
+impl Download {
+    async fn chunk(&mut self) -> Result<Option<Bytes>, Error> {
+        match self {
+            Download::Reqwest(response) => Ok(response.chunk().await?),
+            #[cfg(feature = "curl")]
+            Download::Curl(receiver) => receiver.recv().await.transpose(),
+        }
+    }
+}
+
+#[cfg(feature = "curl")]
+fn header_lines(headermap: &HeaderMap) -> Vec<String> {
+    headermap
+        .iter()
+        .map(|(k, v)| format!("{}: {}", k, String::from_utf8_lossy(v.as_bytes())))
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn process_connection(
     downstream: TcpStream,
-    upstream_client: reqwest::Client,
+    upstream_client: HttpClient,
     headermap: HeaderMap,
     download_headermap: HeaderMap,
     download_upstream: String,
     upstream: String,
     upload_chunk_size: usize,
+    padding: Padding,
 ) -> Result<(), Error> {
     let session_id = uuid::Uuid::new_v4();
 
     let (mut downstream_read, mut downstream_write) = downstream.into_split();
 
     let downloader = async {
-        // some x_padding parameter is needed for compatibility with https://github.com/XTLS/Xray-core/blob/6baad79f9881ee2cf75bdc825b3e2e92b289477a/transport/internet/splithttp/hub.go#L199
-        // TODO add real padding
-        let mut download = upstream_client
-            .get(format!("{download_upstream}/{session_id}?x_padding=0"))
-            .headers(download_headermap)
-            .send()
-            .await?
-            .error_for_status()?;
+        let mut download_headermap = download_headermap;
+        let url = padding.apply(
+            format!("{download_upstream}/{session_id}"),
+            &mut download_headermap,
+        );
+        let mut download = upstream_client.download(url, download_headermap).await?;
 
         loop {
             let upstream_read = download
@@ -98,7 +270,7 @@ async fn process_connection(
 
             if let Some(upstream_read) = upstream_read {
                 downstream_write
-                    .write_all(&*upstream_read)
+                    .write_all(&upstream_read)
                     .await
                     .context("failed to write to downstream")?;
             } else {
@@ -113,7 +285,7 @@ async fn process_connection(
         let mut seq = 0u64;
         loop {
             let downstream_read = downstream_read
-                .read(&mut *downstream_buffer)
+                .read(&mut downstream_buffer)
                 .await
                 .context("failed to read from downstream")?;
 
@@ -122,14 +294,16 @@ async fn process_connection(
                 return Ok::<(), Error>(());
             }
 
-            let response = upstream_client
-                .post(format!("{upstream}/{session_id}/{seq}"))
-                .headers(headermap.clone())
-                .body(downstream_buffer[..downstream_read].to_vec())
-                .send()
+            let mut headermap = headermap.clone();
+            let url = padding.apply(format!("{upstream}/{session_id}/{seq}"), &mut headermap);
+            upstream_client
+                .upload(
+                    url,
+                    headermap,
+                    downstream_buffer[..downstream_read].to_vec(),
+                )
                 .await
                 .context("failed to write to upstream")?;
-            response.error_for_status()?;
 
             seq += 1;
         }
@@ -147,3 +321,46 @@ async fn process_connection(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::resolver::tests::hanging_listener;
+    use crate::{Cli, CliSubcommand};
+
+    fn parse_args(args: &[&str]) -> SplitHttpCli {
+        let cli = Cli::parse_from([&["minidialer", "split-http"], args].concat());
+        let CliSubcommand::SplitHttp(args) = cli.command else {
+            unreachable!()
+        };
+        args
+    }
+
+    #[tokio::test]
+    async fn test_reqwest_connect_timeout() {
+        let (hanging, _listener, _queued) = hanging_listener().await;
+        let url = format!("http://{}/", hanging);
+        let client = reqwest_client(&parse_args(&["--connect-timeout-ms", "100", &url]))
+            .await
+            .unwrap()
+            .current()
+            .await
+            .unwrap();
+
+        let start = Instant::now();
+        let err = client.get(&url).send().await.unwrap_err();
+        assert!(err.is_connect() || err.is_timeout(), "{:?}", err);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        assert!(
+            reqwest_client(&parse_args(&["--happy-eyeballs-delay-ms", "100", &url]))
+                .await
+                .is_err()
+        );
+    }
+}
@@ -8,22 +8,25 @@ use axum::{
     body::{Body, Bytes},
     debug_handler,
     extract::{Path, Query, State},
-    http::Response,
+    http::{header::COOKIE, HeaderMap, Response},
     routing::{get, post},
     Router,
 };
 use futures::task::Poll;
 use futures::StreamExt;
 use serde::Deserialize;
-use tokio::net::TcpStream;
 use tokio::{io::AsyncWriteExt, sync::Mutex};
 use tokio_util::io::ReaderStream;
 
+use crate::resolver::Resolver;
+use crate::splithttp::{PaddingRange, PADDING_HEADER, PADDING_PARAM};
 use crate::SplitHttpServerCli;
 
 pub async fn main(args: SplitHttpServerCli) -> Result<(), Error> {
     let state = AppState {
         upstream: args.upstream.clone(),
+        resolver: Resolver::new(&args.resolver)?,
+        padding: args.padding_bytes,
         upload_sockets: Default::default(),
     };
 
@@ -43,19 +46,29 @@ pub async fn main(args: SplitHttpServerCli) -> Result<(), Error> {
 #[derive(Clone)]
 struct AppState {
     upstream: String,
+    resolver: Resolver,
+    padding: Option<PaddingRange>,
     upload_sockets: Arc<RwLock<HashMap<String, Arc<Mutex<UploadSocket>>>>>,
 }
 
 impl AppState {
+    /// Whether a request with this much padding is allowed by --padding-bytes.
+    fn accepts_padding(&self, padding: Option<usize>) -> bool {
+        match self.padding {
+            Some(range) => padding.is_some_and(|len| range.contains(len)),
+            None => true,
+        }
+    }
+
     async fn upsert_session(self, session_id: String) -> Result<Arc<Mutex<UploadSocket>>, ()> {
         if let Some(session) = self.upload_sockets.read().unwrap().get(&session_id) {
             return Ok(session.clone());
         }
 
-        let upstream = match TcpStream::connect(&self.upstream).await {
+        let upstream = match self.resolver.connect(&self.upstream).await {
             Ok(x) => x,
             Err(e) => {
-                tracing::warn!("failed to connect to upstream: {e}");
+                tracing::warn!("failed to connect to upstream: {e:#}");
                 return Err(());
             }
         };
@@ -120,12 +133,48 @@ struct Params {
     x_padding: Option<String>,
 }
 
+/// The length of the padding that a client sent in the query, the X-Padding header or the
+/// x_padding cookie, if any.
+fn padding_len(params: &Params, headers: &HeaderMap) -> Option<usize> {
+    if let Some(ref padding) = params.x_padding {
+        return Some(padding.len());
+    }
+
+    if let Some(padding) = headers.get(PADDING_HEADER) {
+        return Some(padding.len());
+    }
+
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|cookies| cookies.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .find_map(|cookie| {
+            let (name, value) = cookie.trim().split_once('=')?;
+            (name == PADDING_PARAM).then_some(value.len())
+        })
+}
+
+fn bad_padding(padding: Option<usize>) -> Response<Body> {
+    tracing::debug!("rejecting request with padding {:?}", padding);
+    Response::builder()
+        .status(400)
+        .body(Body::from(()))
+        .unwrap()
+}
+
 #[debug_handler]
 async fn down_handler(
     State(state): State<AppState>,
     Path(session_id): Path<String>,
     Query(params): Query<Params>,
+    headers: HeaderMap,
 ) -> Response<Body> {
+    let padding = padding_len(&params, &headers);
+    if !state.accepts_padding(padding) {
+        return bad_padding(padding);
+    }
+
     let Ok(upload_socket) = state.clone().upsert_session(session_id.clone()).await else {
         return Response::builder()
             .status(502)
@@ -149,7 +198,8 @@ async fn down_handler(
             Poll::Ready(None)
         }));
 
-    let body = if params.x_padding.is_some() {
+    // old clients send no padding, and expect the response to start with "ok"
+    let body = if padding.is_some() {
         Body::from_stream(body_stream)
     } else {
         Body::from_stream(futures::stream::once(async { Ok(Bytes::from("ok")) }).chain(body_stream))
@@ -174,10 +224,17 @@ impl Drop for RemoveUploadSocket {
 async fn up_handler(
     State(state): State<AppState>,
     Path((session_id, seq)): Path<(String, u64)>,
+    Query(params): Query<Params>,
+    headers: HeaderMap,
     body: Bytes,
 ) -> Response<Body> {
     tracing::debug!("up_handler got {} bytes", body.len());
 
+    let padding = padding_len(&params, &headers);
+    if !state.accepts_padding(padding) {
+        return bad_padding(padding);
+    }
+
     let Ok(upload_socket) = state.upsert_session(session_id).await else {
         return Response::builder()
             .status(502)
@@ -220,3 +277,28 @@ async fn up_handler(
         .body(Body::from(()))
         .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn test_padding_len() {
+        let no_query = Params { x_padding: None };
+        let mut headers = HeaderMap::new();
+        assert_eq!(padding_len(&no_query, &headers), None);
+
+        headers.insert(COOKIE, HeaderValue::from_static("a=b; x_padding=XXX"));
+        assert_eq!(padding_len(&no_query, &headers), Some(3));
+
+        headers.insert(PADDING_HEADER, HeaderValue::from_static("XXXXX"));
+        assert_eq!(padding_len(&no_query, &headers), Some(5));
+
+        let query = Params {
+            x_padding: Some(String::new()),
+        };
+        assert_eq!(padding_len(&query, &headers), Some(0));
+    }
+}
@@ -1,25 +1,80 @@
-use std::{cmp, time::Duration};
-
+use std::borrow::Cow;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{ready, Context as TaskContext, Poll};
+use std::time::Duration;
+
+use aho_corasick::automaton::Automaton as _;
+use aho_corasick::Anchored;
 use anyhow::{Context, Error};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::{net::TcpStream, time::sleep};
-
-use crate::TcpFragmentCli;
-
-pub async fn main(args: TcpFragmentCli) {
+use rand::Rng;
+use regex_automata::dfa::{dense, Automaton as _};
+use regex_automata::util::{start, syntax};
+use tokio::io::Interest;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, Sleep};
+
+use crate::client_hello::{self, ClientHello, RECORD_HEADER_LEN};
+use crate::resolver::Resolver;
+use crate::{Desync, Direction, TcpFragmentCli};
+
+pub async fn main(args: TcpFragmentCli) -> Result<(), Error> {
+    // SegmentedStream::write_oob
+    anyhow::ensure!(
+        cfg!(unix) || args.desync.is_none(),
+        "--desync oob is only supported on Unix"
+    );
+    let resolver = Resolver::new(&args.resolver)?;
+    let patterns = Arc::new(SplitPatterns::new(
+        &args.split_after,
+        &args.split_after_regex,
+        &args.split_sni,
+        &args.tls_record_split,
+        args.random_chunks
+            .map(|bytes| {
+                RandomChunks::new(bytes, args.min_chunk, args.max_chunk, args.chunk_delay_ms)
+            })
+            .transpose()?,
+        args.host_prefix.clone(),
+    )?);
+    let sleeps = Arc::new(SleepSchedule::new(
+        args.split_sleep_ms.clone(),
+        args.max_splits,
+    )?);
     let addr = format!("{}:{}", args.common.host, args.common.port);
-    tracing::info!("listening on {}, forwarding to {}", addr, args.upstream,);
+    match args.upstream {
+        Some(ref upstream) => tracing::info!("listening on {}, forwarding to {}", addr, upstream),
+        None => tracing::info!("listening on {}, forwarding by --route", addr),
+    }
 
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
 
     loop {
-        let (socket, _) = listener.accept().await.unwrap();
+        let (mut socket, _) = listener.accept().await.unwrap();
 
         let args = args.clone();
+        let resolver = resolver.clone();
+        let patterns = patterns.clone();
+        let sleeps = sleeps.clone();
 
         tokio::spawn(async move {
             tracing::debug!("new connection");
-            let upstream = match TcpStream::connect(&args.upstream).await {
+            let (upstream, peeked) = match args.upstream {
+                Some(ref upstream) => (upstream.clone(), Vec::new()),
+                None => match route(&mut socket, &args.route).await {
+                    Ok(x) => x,
+                    Err(e) => {
+                        tracing::warn!("rejecting connection: {:?}", e);
+                        return;
+                    }
+                },
+            };
+
+            let upstream = match resolver.connect(&upstream).await {
                 Ok(x) => x,
                 Err(e) => {
                     tracing::warn!("failed to open connection: {:?}", e);
@@ -28,12 +83,18 @@ pub async fn main(args: TcpFragmentCli) {
             };
 
             upstream.set_nodelay(true).unwrap();
+            socket.set_nodelay(true).unwrap();
 
+            // whatever was read for routing still has to be sent
+            let socket = SegmentedStream::new(socket, peeked);
+            let upstream = SegmentedStream::new(upstream, Vec::new());
             if let Err(e) = process_connection(
                 socket,
                 upstream,
-                args.split_after.as_bytes(),
-                args.split_sleep_ms,
+                &patterns,
+                &sleeps,
+                args.direction,
+                args.desync,
             )
             .await
             {
@@ -43,28 +104,63 @@ pub async fn main(args: TcpFragmentCli) {
     }
 }
 
+impl Direction {
+    fn up(self) -> bool {
+        matches!(self, Direction::Up | Direction::Both)
+    }
+
+    fn down(self) -> bool {
+        matches!(self, Direction::Down | Direction::Both)
+    }
+}
+
 async fn process_connection<D, U>(
     mut downstream: D,
     mut upstream: U,
-    split_after: &[u8],
-    split_sleep_ms: u64,
+    patterns: &SplitPatterns,
+    sleeps: &SleepSchedule,
+    direction: Direction,
+    desync: Option<Desync>,
 ) -> Result<usize, Error>
 where
-    D: AsyncRead + AsyncWrite + Unpin,
-    U: AsyncRead + AsyncWrite + Unpin,
+    D: AsyncRead + FragmentWrite,
+    U: AsyncRead + FragmentWrite,
 {
     let mut upstream_buffer = Box::new([0u8; 65536]);
     let mut downstream_buffer = Box::new([0u8; 65536]);
-    let mut downstream_match_offset = 0;
+    let mut outbound = direction.up().then(|| Fragmenter::new(patterns, sleeps));
+    let mut inbound = direction.down().then(|| Fragmenter::new(patterns, sleeps));
+    let (mut outbound_desync, mut inbound_desync) = (desync, desync);
     let mut sleep_count = 0;
 
-    let mut do_sleep = || {
-        if cfg!(test) {
-            sleep_count += 1;
+    // requests only go up
+    let mut host_rewriter = patterns
+        .host_prefix
+        .as_deref()
+        .filter(|_| direction.up())
+        .map(HostRewriter::new);
+
+    // the ClientHello only exists in the outbound direction
+    if let Some(ref mut outbound) = outbound {
+        if !patterns.sni.is_empty() || !patterns.records.is_empty() {
+            let mut first = read_first_record(&mut downstream).await?;
+            let mut offsets = sni_splits(&first, &patterns.sni);
+            if !patterns.records.is_empty() {
+                first = split_record(&first, &patterns.records, &mut offsets);
+            }
+            // the rewriter leaves TLS unchanged, and there are no SNI offsets in HTTP
+            if let Some(ref mut host_rewriter) = host_rewriter {
+                let (rewritten, host_offsets) = host_rewriter.feed(&first);
+                first = rewritten;
+                offsets.extend(host_offsets);
+            }
+            let splits = outbound.find_splits(&first, offsets);
+
+            sleep_count += write_fragments(&mut upstream, &first, &splits, &mut outbound_desync)
+                .await
+                .context("failed to write to upstream")?;
         }
-        tracing::debug!("sleeping");
-        sleep(Duration::from_millis(split_sleep_ms))
-    };
+    }
 
     'main: loop {
         tokio::select! {
@@ -75,294 +171,1579 @@ where
                     break 'main;
                 }
 
-                downstream.write_all(&upstream_buffer[..upstream_read]).await.context("failed to write to downstream")?;
+                let upstream_buffer = &upstream_buffer[..upstream_read];
+
+                let splits = inbound.as_mut().map_or(Vec::new(), |f| f.find_splits(upstream_buffer, Vec::new()));
+                sleep_count += write_fragments(&mut downstream, upstream_buffer, &splits, &mut inbound_desync).await.context("failed to write to downstream")?;
             }
             downstream_read = downstream.read(&mut *downstream_buffer) => {
                 let downstream_read = downstream_read.context("failed to read from downstream")?;
 
                 if downstream_read == 0 {
                     tracing::debug!("empty read from downstream");
+                    // an incomplete request head that was held back
+                    if let Some(rest) = host_rewriter.as_mut().map(HostRewriter::finish) {
+                        upstream.write_all(&rest).await.context("failed to write to upstream")?;
+                    }
                     break 'main;
                 }
 
                 // just to be sure we will never double-read data
-                let mut downstream_buffer = &downstream_buffer[..downstream_read];
+                let downstream_buffer = &downstream_buffer[..downstream_read];
 
-                while !downstream_buffer.is_empty() {
-                    let buffer_match_prefix = &downstream_buffer[..cmp::min(split_after.len() - downstream_match_offset, downstream_buffer.len())];
+                let (downstream_buffer, offsets) = match host_rewriter {
+                    Some(ref mut host_rewriter) => {
+                        let (rewritten, offsets) = host_rewriter.feed(downstream_buffer);
+                        (Cow::Owned(rewritten), offsets)
+                    }
+                    None => (Cow::Borrowed(downstream_buffer), Vec::new()),
+                };
 
-                    if split_after[downstream_match_offset..].starts_with(buffer_match_prefix) {
-                        tracing::debug!("found split match at beginning of buffer");
+                let splits = outbound.as_mut().map_or(Vec::new(), |f| f.find_splits(&downstream_buffer, offsets));
+                sleep_count += write_fragments(&mut upstream, &downstream_buffer, &splits, &mut outbound_desync).await.context("failed to write to upstream")?;
+            }
+        }
+    }
 
-                        downstream_match_offset += buffer_match_prefix.len();
+    Ok(sleep_count)
+}
 
-                        upstream.write_all(buffer_match_prefix).await.context("failed to write to upstream")?;
+/// An offset into a buffer to split at, and how many milliseconds to sleep after it.
+type Split = (usize, u64);
 
-                        if downstream_match_offset == split_after.len() {
-                            do_sleep().await;
-                            downstream_match_offset = 0;
-                        }
+/// The fragmentation state of one direction of a connection.
+struct Fragmenter<'a> {
+    splitter: Splitter<'a>,
+    chunker: Option<Chunker<'a>>,
+    sleeps: &'a SleepSchedule,
+    /// The number of pattern splits so far, for the sleep schedule.
+    split_count: usize,
+}
 
-                        downstream_buffer = &downstream_buffer[buffer_match_prefix.len()..];
-                    } else if let Some(mut idx) = downstream_buffer.windows(split_after.len()).position(|window| window == split_after) {
-                        downstream_match_offset = 0;
-
-                        tracing::debug!("found split match in the middle of buffer");
-
-                        idx += split_after.len();
-
-                        upstream.write_all(&downstream_buffer[..idx]).await.context("failed to write to upstream")?;
-                        do_sleep().await;
-                        downstream_buffer = &downstream_buffer[idx..];
-                    } else {
-                        for overlap in (1..cmp::min(downstream_buffer.len(), split_after.len()) - 1).rev() {
-                            if &downstream_buffer[(downstream_buffer.len() - overlap)..] == &split_after[..overlap] {
-                                tracing::debug!("found split match at end of buffer, of length {}", overlap);
-                                downstream_match_offset = overlap;
-                                upstream.write_all(downstream_buffer).await.context("failed to write to upstream")?;
-                                continue 'main;
-                            }
-                        }
+impl<'a> Fragmenter<'a> {
+    fn new(patterns: &'a SplitPatterns, sleeps: &'a SleepSchedule) -> Self {
+        Fragmenter {
+            splitter: Splitter::new(patterns),
+            chunker: patterns.chunks.as_ref().map(Chunker::new),
+            sleeps,
+            split_count: 0,
+        }
+    }
 
-                        downstream_match_offset = 0;
+    /// Feed the next chunk of the stream, and return where to split it and how long to sleep, in
+    /// ascending order. offsets are additional splits that were found by other means, like the
+    /// SNI splits. Pattern splits sleep according to the schedule, random chunks use their own
+    /// delay. Where both split at the same offset, the longer sleep wins.
+    fn find_splits(&mut self, buf: &[u8], mut offsets: Vec<usize>) -> Vec<Split> {
+        offsets.extend(self.splitter.find_splits(buf));
+        offsets.sort();
+        offsets.dedup();
+
+        let chunks = self
+            .chunker
+            .as_mut()
+            .map_or(Vec::new(), |chunker| chunker.find_splits(buf));
+
+        let mut splits = offsets
+            .into_iter()
+            .map_while(|offset| {
+                let sleep_ms = self.sleeps.sleep_ms(self.split_count)?;
+                self.split_count += 1;
+                Some((offset, sleep_ms))
+            })
+            .chain(chunks)
+            .collect::<Vec<_>>();
+        splits.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+        splits.dedup_by_key(|split| split.0);
+        splits
+    }
+}
 
-                        tracing::debug!("found no match");
-                        upstream.write_all(downstream_buffer).await.context("failed to write to upstream")?;
-                        break;
-                    }
-                }
-            }
+/// Write buf to the writer, flushing, desyncing and sleeping after each of the given splits.
+/// Returns the number of splits.
+///
+/// desync is only done once and then set to None: a receiver only takes the latest urgent byte
+/// out of the stream, an earlier one that it has not read past yet becomes part of the data.
+async fn write_fragments<W>(
+    writer: &mut W,
+    buf: &[u8],
+    splits: &[Split],
+    desync: &mut Option<Desync>,
+) -> io::Result<usize>
+where
+    W: FragmentWrite,
+{
+    let mut start = 0;
+    for &(end, sleep_ms) in splits {
+        tracing::debug!("found split match");
+        writer.write_all(&buf[start..end]).await?;
+        // with a SegmentedStream, this ends the TCP segment
+        writer.flush().await?;
+        if let Some(Desync::Oob) = desync.take() {
+            writer.write_oob(OOB_BYTE).await?;
+            writer.flush().await?;
+        }
+        if sleep_ms > 0 {
+            tracing::debug!("sleeping {}ms", sleep_ms);
+            sleep(Duration::from_millis(sleep_ms)).await;
         }
+        start = end;
     }
 
-    Ok(sleep_count)
+    if start < buf.len() {
+        writer.write_all(&buf[start..]).await?;
+    }
+
+    Ok(splits.len())
 }
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        fmt, io,
-        pin::Pin,
-        task::{Context, Poll},
-    };
+/// The byte that --desync oob sends. The receiver never sees it, so any byte works.
+const OOB_BYTE: u8 = b'x';
 
-    use tokio::io::{join, ReadBuf};
-    use tracing_test::traced_test;
+/// Where fragments are written to: the client or upstream connection, or a test double.
+trait FragmentWrite: AsyncWrite + Unpin {
+    /// Send a single byte as TCP urgent data, after everything written so far.
+    async fn write_oob(&mut self, _byte: u8) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
 
-    use super::*;
+impl<T: FragmentWrite + ?Sized> FragmentWrite for &mut T {
+    async fn write_oob(&mut self, byte: u8) -> io::Result<()> {
+        (**self).write_oob(byte).await
+    }
+}
 
-    /// An AsyncRead that always returns pending
-    struct Nothing;
+impl<R: AsyncRead + Unpin, W: FragmentWrite> FragmentWrite for tokio::io::Join<R, W> {
+    async fn write_oob(&mut self, byte: u8) -> io::Result<()> {
+        self.writer_mut().write_oob(byte).await
+    }
+}
 
-    impl AsyncRead for Nothing {
-        fn poll_read(
-            self: Pin<&mut Self>,
-            _cx: &mut Context<'_>,
-            _buf: &mut ReadBuf<'_>,
-        ) -> Poll<io::Result<()>> {
-            Poll::Pending
+impl FragmentWrite for Vec<u8> {}
+
+/// A connection to write fragments to. Flushing it waits until the kernel has sent everything
+/// that was written so far, so that the next write is guaranteed to start a new TCP segment, no
+/// matter how soon it comes. Together with TCP_NODELAY, this makes each fragment its own segment.
+///
+/// Outside of Linux, flushing does nothing and the fragments rely on the sleep.
+struct SegmentedStream {
+    inner: TcpStream,
+    /// Bytes that were already read from inner, like for --route, and are read again first.
+    peeked: io::Cursor<Vec<u8>>,
+    /// There is no readiness event for an empty send queue, so it is polled with this timer.
+    retry: Option<Pin<Box<Sleep>>>,
+}
+
+impl SegmentedStream {
+    fn new(inner: TcpStream, peeked: Vec<u8>) -> Self {
+        SegmentedStream {
+            inner,
+            peeked: io::Cursor::new(peeked),
+            retry: None,
         }
     }
+}
 
-    /// An AsyncWrite that makes each individual write, and how they are fragmented, visible for
-    /// inspection.
-    #[derive(Default)]
-    struct Fragments(Vec<Vec<u8>>);
+#[cfg(not(unix))]
+impl FragmentWrite for SegmentedStream {}
+
+#[cfg(unix)]
+impl FragmentWrite for SegmentedStream {
+    /// With MSG_OOB. Unless the receiver has set SO_OOBINLINE, its kernel takes the byte out of
+    /// the stream, but middleboxes that reassemble the stream may keep it.
+    async fn write_oob(&mut self, byte: u8) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let fd = self.inner.as_raw_fd();
+        self.inner
+            .async_io(Interest::WRITABLE, || {
+                // SAFETY: sends a single byte from the stack
+                let rv = unsafe {
+                    libc::send(
+                        fd,
+                        &byte as *const u8 as *const libc::c_void,
+                        1,
+                        libc::MSG_OOB,
+                    )
+                };
+                if rv < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            })
+            .await
+    }
+}
 
-    impl PartialEq<Vec<Vec<u8>>> for Fragments {
-        fn eq(&self, other: &Vec<Vec<u8>>) -> bool {
-            self.0 == *other
-        }
+/// The number of bytes in the send queue that have not been sent yet.
+#[cfg(target_os = "linux")]
+fn unsent_bytes(stream: &TcpStream) -> io::Result<usize> {
+    use std::os::fd::AsRawFd;
+
+    // not in libc, from linux/sockios.h
+    const SIOCOUTQNSD: libc::c_ulong = 0x894b;
+
+    let mut unsent: libc::c_int = 0;
+    // SAFETY: SIOCOUTQNSD writes a single int
+    let rv = unsafe { libc::ioctl(stream.as_raw_fd(), SIOCOUTQNSD as _, &mut unsent) };
+    if rv < 0 {
+        return Err(io::Error::last_os_error());
     }
+    Ok(unsent as usize)
+}
 
-    impl fmt::Debug for Fragments {
-        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-            writeln!(f, "Fragments([")?;
-            for fragment in &self.0 {
-                writeln!(f, "  {:?},", String::from_utf8_lossy(fragment))?;
-            }
+#[cfg(not(target_os = "linux"))]
+fn unsent_bytes(_stream: &TcpStream) -> io::Result<usize> {
+    Ok(0)
+}
 
-            writeln!(f, "])")
+impl AsyncRead for SegmentedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if (self.peeked.position() as usize) < self.peeked.get_ref().len() {
+            return Pin::new(&mut self.peeked).poll_read(cx, buf);
         }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
     }
+}
 
-    impl AsyncRead for Fragments {
-        fn poll_read(
-            mut self: Pin<&mut Self>,
-            cx: &mut Context<'_>,
-            buf: &mut ReadBuf<'_>,
-        ) -> Poll<io::Result<()>> {
-            if let Some(next) = self.0.first_mut() {
-                assert!(!next.is_empty());
-                let mut next2 = next.as_slice();
-                let rv = std::pin::pin!(&mut next2).poll_read(cx, buf);
-                if next2.is_empty() {
-                    self.0.remove(0);
-                } else {
-                    *next = next2.to_vec();
-                }
-                assert!(matches!(rv, Poll::Ready(Ok(()))));
-                return rv;
+impl AsyncWrite for SegmentedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        ready!(Pin::new(&mut self.inner).poll_flush(cx))?;
+
+        loop {
+            if let Some(retry) = self.retry.as_mut() {
+                ready!(retry.as_mut().poll(cx));
+                self.retry = None;
             }
 
-            Poll::Ready(Ok(()))
+            if unsent_bytes(&self.inner)? == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            self.retry = Some(Box::pin(sleep(Duration::from_millis(1))));
         }
     }
 
-    impl AsyncWrite for Fragments {
-        fn poll_write(
-            mut self: Pin<&mut Self>,
-            _cx: &mut Context<'_>,
-            buf: &[u8],
-        ) -> Poll<io::Result<usize>> {
-            self.0.push(buf.to_vec());
-            Poll::Ready(Ok(buf.len()))
-        }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
 
-        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-            Poll::Ready(Ok(()))
+/// Read from the client until the first TLS record is complete. Returns early on EOF, or if the
+/// client does not speak TLS. May return more than one record.
+async fn read_first_record<D>(downstream: &mut D) -> Result<Vec<u8>, Error>
+where
+    D: AsyncRead + Unpin,
+{
+    let mut first = Vec::new();
+    let mut buf = [0u8; 4096];
+
+    loop {
+        match client_hello::record_len(&first) {
+            Some(Ok(record_len)) if first.len() >= record_len => break,
+            Some(Err(e)) => {
+                tracing::debug!("not splitting SNI: {}", e);
+                break;
+            }
+            _ => {}
         }
 
-        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-            Poll::Ready(Ok(()))
+        let read = downstream
+            .read(&mut buf)
+            .await
+            .context("failed to read from downstream")?;
+        if read == 0 {
+            break;
         }
+        first.extend_from_slice(&buf[..read]);
     }
 
-    #[tokio::test]
-    async fn test_split_begin() {
-        let mut downloaded = Vec::new();
-        let mut client = join(b"www.speedtest.net.example.com".as_slice(), &mut downloaded);
+    Ok(first)
+}
 
-        let mut uploaded = Fragments::default();
-        let mut server = join(Nothing, &mut uploaded);
+/// The most that is read from a client to find the Host header of its HTTP request.
+const MAX_HEADER_LEN: usize = 16384;
 
-        process_connection(&mut client, &mut server, b"www.speedtest.net", 0)
-            .await
-            .unwrap();
+/// A rule from --route.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    /// A hostname, or a suffix if it starts with a dot. Lowercase.
+    pattern: String,
+    upstream: String,
+}
 
-        assert_eq!(downloaded, b"");
-        assert_eq!(
-            uploaded,
-            vec![b"www.speedtest.net".to_vec(), b".example.com".to_vec(),]
-        );
+impl Route {
+    fn matches(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if self.pattern.starts_with('.') {
+            host.ends_with(&self.pattern)
+        } else {
+            host == self.pattern
+        }
     }
+}
 
-    #[tokio::test]
-    async fn test_split_middle() {
-        let mut downloaded = Vec::new();
-        let mut client = join(
-            b"Host: www.speedtest.net.example.com".as_slice(),
-            &mut downloaded,
-        );
-
-        let mut uploaded = Fragments::default();
-        let mut server = join(Nothing, &mut uploaded);
-
-        process_connection(&mut client, &mut server, b"www.speedtest.net", 0)
-            .await
-            .unwrap();
-
-        assert_eq!(downloaded, b"");
-        assert_eq!(
-            uploaded,
-            vec![
-                b"Host: www.speedtest.net".to_vec(),
-                b".example.com".to_vec(),
-            ]
-        );
+impl FromStr for Route {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Error> {
+        let (pattern, upstream) = value
+            .split_once('=')
+            .filter(|(pattern, upstream)| !pattern.is_empty() && !upstream.is_empty())
+            .with_context(|| format!("expected PATTERN=UPSTREAM, got {:?}", value))?;
+        Ok(Route {
+            pattern: pattern.trim_end_matches('.').to_ascii_lowercase(),
+            upstream: upstream.to_owned(),
+        })
     }
+}
 
-    #[tokio::test]
-    async fn test_split_partial_end() {
-        let mut downloaded = Vec::new();
-        let mut client = join(b"Host: www.speedtes".as_slice(), &mut downloaded);
+/// Pick the upstream for a new connection from the routes, by the hostname the client asks for.
+/// Returns the upstream, and the bytes that had to be read from the client to find it.
+async fn route<D>(downstream: &mut D, routes: &[Route]) -> Result<(String, Vec<u8>), Error>
+where
+    D: AsyncRead + Unpin,
+{
+    let (peeked, host) = read_host(downstream).await?;
+    let host = host.context("client did not send a hostname")?;
+    let route = routes
+        .iter()
+        .find(|route| route.matches(&host))
+        .with_context(|| format!("no --route for {}", host))?;
+
+    tracing::debug!("routing {} to {}", host, route.upstream);
+    Ok((route.upstream.clone(), peeked))
+}
 
-        let mut uploaded = Fragments::default();
-        let mut server = join(Nothing, &mut uploaded);
+/// Read from the client until the hostname it wants to reach is known, from the SNI of a TLS
+/// ClientHello or the Host header of an HTTP request. Returns everything that was read, and the
+/// hostname if there is one.
+async fn read_host<D>(downstream: &mut D) -> Result<(Vec<u8>, Option<String>), Error>
+where
+    D: AsyncRead + Unpin,
+{
+    let mut first = read_first_record(downstream).await?;
+    if let Some(Ok(_)) = client_hello::record_len(&first) {
+        let host = ClientHello::parse(&first)
+            .ok()
+            .and_then(|hello| hello.sni(&first).map(str::to_owned));
+        return Ok((first, host));
+    }
 
-        let sleep_count = process_connection(&mut client, &mut server, b"www.speedtest.net", 0)
+    let mut buf = [0u8; 4096];
+    while !first.windows(4).any(|w| w == b"\r\n\r\n") && first.len() < MAX_HEADER_LEN {
+        let read = downstream
+            .read(&mut buf)
             .await
-            .unwrap();
-
-        assert_eq!(downloaded, b"");
-        assert_eq!(uploaded, vec![b"Host: www.speedtes".to_vec(),]);
-        assert_eq!(sleep_count, 0);
+            .context("failed to read from downstream")?;
+        if read == 0 {
+            break;
+        }
+        first.extend_from_slice(&buf[..read]);
     }
 
-    #[tokio::test]
-    #[traced_test]
-    async fn test_split_partial_end2() {
-        let mut downloaded = Vec::new();
-        let mut client = join(
-            Fragments(vec![
-                b"Host: www.speedtes".to_vec(),
-                b"t.net.example.com".to_vec(),
-            ]),
-            &mut downloaded,
-        );
+    let host = host_header(&first);
+    Ok((first, host))
+}
 
-        let mut uploaded = Fragments::default();
-        let mut server = join(Nothing, &mut uploaded);
+/// The value of the Host header of an HTTP request, without the port.
+fn host_header(request: &[u8]) -> Option<String> {
+    let end = request
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .unwrap_or(request.len());
+    let head = std::str::from_utf8(&request[..end]).ok()?;
+
+    let value = head.split("\r\n").skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.eq_ignore_ascii_case("host").then(|| value.trim())
+    })?;
+
+    let host = match value.strip_prefix('[') {
+        // an IPv6 address, with or without a port
+        Some(rest) => rest.split_once(']')?.0,
+        None => value.split(':').next()?,
+    };
+    Some(host.to_owned())
+}
 
-        let sleep_count = process_connection(&mut client, &mut server, b"www.speedtest.net", 0)
-            .await
-            .unwrap();
+/// Prepends `prefix.` to the Host header value of an HTTP request, unless it already starts with
+/// it. Returns the request and the offset right after the prefix.
+fn rewrite_host(request: &[u8], prefix: &str) -> Option<(Vec<u8>, usize)> {
+    let end = request.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let head = &request[..end];
+
+    // skip the request line
+    let mut line_start = head.windows(2).position(|w| w == b"\r\n")? + 2;
+    while line_start < head.len() {
+        let line_end = head[line_start..]
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .map_or(head.len(), |pos| line_start + pos);
+        let line = &head[line_start..line_end];
+
+        if line.len() >= 5 && line[..5].eq_ignore_ascii_case(b"host:") {
+            let whitespace = line[5..]
+                .iter()
+                .take_while(|&&b| b == b' ' || b == b'\t')
+                .count();
+            let value = line_start + 5 + whitespace;
+            let decoy = format!("{}.", prefix);
+
+            let mut rewritten = request.to_vec();
+            let value_bytes = &request[value..line_end];
+            if value_bytes.len() < decoy.len()
+                || !value_bytes[..decoy.len()].eq_ignore_ascii_case(decoy.as_bytes())
+            {
+                rewritten.splice(value..value, decoy.bytes());
+            }
+            return Some((rewritten, value + prefix.len()));
+        }
 
-        assert_eq!(downloaded, b"");
-        assert_eq!(
-            uploaded,
-            vec![
-                b"Host: www.speedtes".to_vec(),
-                b"t.net".to_vec(),
-                b".example.com".to_vec(),
-            ]
-        );
-        assert_eq!(sleep_count, 1);
+        line_start = line_end + 2;
     }
 
-    #[tokio::test]
-    #[traced_test]
-    async fn test_split_three() {
-        let mut downloaded = Vec::new();
-        let mut client = join(
-            Fragments(vec![
-                b"Host: www.".to_vec(),
-                b"speedtes".to_vec(),
-                b"t.net.example.com".to_vec(),
-            ]),
-            &mut downloaded,
-        );
+    None
+}
 
-        let mut uploaded = Fragments::default();
-        let mut server = join(Nothing, &mut uploaded);
+/// Rewrites the Host header of every HTTP/1.x request that the client sends, for --host-prefix.
+/// Request heads are held back until they are complete, bodies are skipped by their
+/// Content-Length or chunked encoding. Anything that is not HTTP/1.x, and everything after a
+/// CONNECT or upgrade request, is forwarded unchanged.
+struct HostRewriter<'a> {
+    prefix: &'a str,
+    state: RewriteState,
+    /// The incomplete request head, chunk size line or trailer line.
+    pending: Vec<u8>,
+}
 
-        let sleep_count = process_connection(&mut client, &mut server, b"www.speedtest.net", 0)
-            .await
-            .unwrap();
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RewriteState {
+    Head,
+    /// This many bytes of a body are left.
+    Body(u64),
+    ChunkSize,
+    /// This many bytes of chunk data and its CRLF are left.
+    ChunkData(u64),
+    Trailers,
+    Passthrough,
+}
 
-        assert_eq!(downloaded, b"");
-        assert_eq!(
-            uploaded,
-            vec![
-                b"Host: www.".to_vec(),
-                b"speedtes".to_vec(),
-                b"t.net".to_vec(),
-                b".example.com".to_vec(),
-            ]
-        );
-        assert_eq!(sleep_count, 1);
+impl<'a> HostRewriter<'a> {
+    fn new(prefix: &'a str) -> Self {
+        HostRewriter {
+            prefix,
+            state: RewriteState::Head,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Feed the next chunk of the stream, and return what to forward of it, with the offsets
+    /// right after each inserted prefix.
+    fn feed(&mut self, mut input: &[u8]) -> (Vec<u8>, Vec<usize>) {
+        let mut output = Vec::with_capacity(input.len() + self.pending.len());
+        let mut offsets = Vec::new();
+
+        while !input.is_empty() {
+            match self.state {
+                RewriteState::Passthrough => {
+                    output.extend_from_slice(input);
+                    break;
+                }
+                RewriteState::Body(left) | RewriteState::ChunkData(left) => {
+                    let taken = input.len().min(left.try_into().unwrap_or(usize::MAX));
+                    output.extend_from_slice(&input[..taken]);
+                    input = &input[taken..];
+
+                    let left = left - taken as u64;
+                    self.state = match self.state {
+                        RewriteState::Body(_) if left == 0 => RewriteState::Head,
+                        RewriteState::Body(_) => RewriteState::Body(left),
+                        _ if left == 0 => RewriteState::ChunkSize,
+                        _ => RewriteState::ChunkData(left),
+                    };
+                }
+                RewriteState::Head => {
+                    let complete = self.take_until(&mut input, b"\r\n\r\n");
+                    if !is_request_start(&self.pending) {
+                        self.give_up(&mut output, "the client does not speak HTTP/1.x");
+                        continue;
+                    } else if !complete {
+                        continue;
+                    }
+
+                    let head = std::mem::take(&mut self.pending);
+                    self.state = next_state(&head);
+                    match rewrite_host(&head, self.prefix) {
+                        Some((rewritten, offset)) => {
+                            offsets.push(output.len() + offset);
+                            output.extend_from_slice(&rewritten);
+                        }
+                        None => {
+                            tracing::debug!("not rewriting Host, no Host header found");
+                            output.extend_from_slice(&head);
+                        }
+                    }
+                }
+                RewriteState::ChunkSize => {
+                    if !self.take_until(&mut input, b"\r\n") {
+                        if self.pending.len() > MAX_HEADER_LEN {
+                            self.give_up(&mut output, "chunk size line too long");
+                        }
+                        continue;
+                    }
+
+                    let line = std::mem::take(&mut self.pending);
+                    let size = std::str::from_utf8(&line).ok().and_then(|line| {
+                        let size = line.trim_end().split(';').next()?;
+                        u64::from_str_radix(size.trim(), 16).ok()
+                    });
+                    output.extend_from_slice(&line);
+                    self.state = match size {
+                        Some(0) => RewriteState::Trailers,
+                        Some(size) => RewriteState::ChunkData(size.saturating_add(2)),
+                        None => {
+                            self.give_up(&mut output, "invalid chunk size");
+                            continue;
+                        }
+                    };
+                }
+                RewriteState::Trailers => {
+                    if !self.take_until(&mut input, b"\r\n") {
+                        if self.pending.len() > MAX_HEADER_LEN {
+                            self.give_up(&mut output, "trailer line too long");
+                        }
+                        continue;
+                    }
+
+                    let line = std::mem::take(&mut self.pending);
+                    if line == b"\r\n" {
+                        self.state = RewriteState::Head;
+                    }
+                    output.extend_from_slice(&line);
+                }
+            }
+        }
+
+        (output, offsets)
+    }
+
+    /// Stop rewriting: forward what was held back, and everything after it unchanged.
+    fn give_up(&mut self, output: &mut Vec<u8>, reason: &str) {
+        tracing::debug!("not rewriting Host anymore, {}", reason);
+        output.append(&mut self.pending);
+        self.state = RewriteState::Passthrough;
+    }
+
+    /// Move input into pending up to and including delimiter, and return whether it was found.
+    fn take_until(&mut self, input: &mut &[u8], delimiter: &[u8]) -> bool {
+        // the delimiter may have started in an earlier chunk
+        let start = self.pending.len().saturating_sub(delimiter.len() - 1);
+        let before = self.pending.len();
+        self.pending.extend_from_slice(input);
+
+        match self.pending[start..]
+            .windows(delimiter.len())
+            .position(|w| w == delimiter)
+        {
+            Some(pos) => {
+                let end = start + pos + delimiter.len();
+                self.pending.truncate(end);
+                *input = &input[end - before..];
+                true
+            }
+            None => {
+                *input = &[];
+                false
+            }
+        }
+    }
+
+    /// What was held back at the end of the stream.
+    fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Whether the start of a request head can be HTTP/1.x: a method in capitals, and a request
+/// line that ends in the version once it is complete.
+fn is_request_start(head: &[u8]) -> bool {
+    if head.len() > MAX_HEADER_LEN || !head.first().is_some_and(u8::is_ascii_uppercase) {
+        return false;
+    }
+
+    match head.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => head[..end].ends_with(b" HTTP/1.1") || head[..end].ends_with(b" HTTP/1.0"),
+        None => true,
+    }
+}
+
+/// What follows a complete request head.
+fn next_state(head: &[u8]) -> RewriteState {
+    let Ok(head) = std::str::from_utf8(head) else {
+        return RewriteState::Passthrough;
+    };
+    if head.starts_with("CONNECT ") {
+        return RewriteState::Passthrough;
+    }
+
+    let mut state = RewriteState::Head;
+    for line in head.trim_end().split("\r\n").skip(1) {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+
+        if name.eq_ignore_ascii_case("upgrade") {
+            return RewriteState::Passthrough;
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            // chunked has to be the last coding, and takes precedence over Content-Length
+            if value.to_ascii_lowercase().ends_with("chunked") {
+                return RewriteState::ChunkSize;
+            }
+            return RewriteState::Passthrough;
+        } else if name.eq_ignore_ascii_case("content-length") {
+            state = match value.parse() {
+                Ok(0) => RewriteState::Head,
+                Ok(len) => RewriteState::Body(len),
+                Err(_) => return RewriteState::Passthrough,
+            };
+        }
+    }
+    state
+}
+
+/// Where to split the first TLS record for --split-sni, relative to the server_name extension.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SniSplit {
+    /// After this many bytes of the hostname.
+    Offset(usize),
+    /// In the middle of the hostname.
+    Middle,
+    /// Right after the server_name extension.
+    End,
+}
+
+impl FromStr for SniSplit {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Error> {
+        match value {
+            "middle" => Ok(SniSplit::Middle),
+            "end" => Ok(SniSplit::End),
+            _ => value
+                .parse()
+                .map(SniSplit::Offset)
+                .map_err(|_| anyhow::anyhow!("expected middle, end or a number, got {:?}", value)),
+        }
+    }
+}
+
+/// The offsets into the first record to split at, in ascending order. Empty if the record is no
+/// ClientHello or has no SNI.
+fn sni_splits(first: &[u8], points: &[SniSplit]) -> Vec<usize> {
+    let hello = match ClientHello::parse(first) {
+        Ok(hello) => hello,
+        Err(e) => {
+            tracing::debug!("not splitting SNI, failed to parse ClientHello: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let (Some(name), Some(extension)) = (hello.server_name, hello.server_name_extension) else {
+        tracing::debug!("not splitting SNI, ClientHello has no SNI");
+        return Vec::new();
+    };
+
+    let mut splits = points
+        .iter()
+        .map(|point| match *point {
+            SniSplit::Offset(offset) => name.start + offset.min(name.len()),
+            SniSplit::Middle => name.start + name.len() / 2,
+            SniSplit::End => extension.end,
+        })
+        // splitting at the end of the record only delays whatever comes after it
+        .filter(|&offset| offset > 0 && offset < hello.record_len)
+        .collect::<Vec<_>>();
+    splits.sort();
+    splits.dedup();
+    splits
+}
+
+/// Where to end a TLS record for --tls-record-split.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordSplit {
+    /// After this many bytes of the handshake data.
+    Offset(usize),
+    /// At a random offset, chosen for each connection.
+    Random,
+    /// At a point relative to the SNI, like --split-sni.
+    Sni(SniSplit),
+}
+
+impl FromStr for RecordSplit {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Error> {
+        match value {
+            "random" => Ok(RecordSplit::Random),
+            "sni" => Ok(RecordSplit::Sni(SniSplit::Middle)),
+            _ => match value.strip_prefix("sni:") {
+                Some(point) => point.parse().map(RecordSplit::Sni),
+                None => value.parse().map(RecordSplit::Offset).map_err(|_| {
+                    anyhow::anyhow!(
+                        "expected a number, random, sni or sni:POINT, got {:?}",
+                        value
+                    )
+                }),
+            },
+        }
+    }
+}
+
+/// Rewrite the first TLS record into several records that end at the given points, so that the
+/// ClientHello spans multiple records. Data after the first record is kept as is. The offsets in
+/// tcp_splits are moved along with the data they point to.
+///
+/// Returns first unchanged if it does not start with a complete handshake record.
+fn split_record(first: &[u8], points: &[RecordSplit], tcp_splits: &mut [usize]) -> Vec<u8> {
+    let record_len = match client_hello::record_len(first) {
+        Some(Ok(record_len)) if first.len() >= record_len => record_len,
+        _ => {
+            tracing::debug!("not splitting TLS record, no complete handshake record");
+            return first.to_vec();
+        }
+    };
+
+    let mut ends = points
+        .iter()
+        .flat_map(|point| match *point {
+            RecordSplit::Offset(offset) => vec![RECORD_HEADER_LEN + offset],
+            RecordSplit::Random if record_len > RECORD_HEADER_LEN + 1 => {
+                vec![rand::thread_rng().gen_range(RECORD_HEADER_LEN + 1..record_len)]
+            }
+            RecordSplit::Random => Vec::new(),
+            RecordSplit::Sni(point) => sni_splits(first, &[point]),
+        })
+        // records must not be empty
+        .filter(|&end| end > RECORD_HEADER_LEN && end < record_len)
+        .collect::<Vec<_>>();
+    ends.sort();
+    ends.dedup();
+    ends.push(record_len);
+
+    let mut records = Vec::with_capacity(first.len() + ends.len() * RECORD_HEADER_LEN);
+    let mut start = RECORD_HEADER_LEN;
+    for &end in &ends {
+        // content type and legacy version stay the same
+        records.extend_from_slice(&first[..3]);
+        records.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        records.extend_from_slice(&first[start..end]);
+        start = end;
+    }
+    records.extend_from_slice(&first[record_len..]);
+
+    // every new record header before an offset shifts it. a TCP split right at the end of a
+    // record stays in front of the next header. the last record reuses the original header.
+    let boundaries = &ends[..ends.len() - 1];
+    for split in tcp_splits {
+        *split += RECORD_HEADER_LEN * boundaries.iter().filter(|&&end| end < *split).count();
+    }
+
+    records
+}
+
+/// How many milliseconds to sleep, from --split-sleep-ms and --chunk-delay-ms. A new value is
+/// picked for every sleep, so that the timing is harder to fingerprint.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delay {
+    /// MS, or MIN-MAX for a uniformly distributed value.
+    Uniform { min: u64, max: u64 },
+    /// MEAN~STDDEV for a normally distributed value, cut off at 0.
+    Normal { mean: f64, std_dev: f64 },
+}
+
+impl Delay {
+    fn sample(&self) -> u64 {
+        let mut rng = rand::thread_rng();
+        match *self {
+            Delay::Uniform { min, max } => rng.gen_range(min..=max),
+            Delay::Normal { mean, std_dev } => {
+                // Box-Muller transform, 1 - u1 keeps the logarithm finite
+                let (u1, u2) = (1.0 - rng.gen::<f64>(), rng.gen::<f64>());
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+                (mean + z * std_dev).max(0.0).round() as u64
+            }
+        }
+    }
+}
+
+impl FromStr for Delay {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Error> {
+        let invalid = || anyhow::anyhow!("expected MS, MIN-MAX or MEAN~STDDEV, got {:?}", value);
+        let parse = |ms: &str| ms.parse::<u64>().map_err(|_| invalid());
+
+        if let Some((mean, std_dev)) = value.split_once('~') {
+            let (mean, std_dev) = (parse(mean)? as f64, parse(std_dev)? as f64);
+            return Ok(Delay::Normal { mean, std_dev });
+        }
+
+        let (min, max) = match value.split_once('-') {
+            Some((min, max)) => (parse(min)?, parse(max)?),
+            None => (parse(value)?, parse(value)?),
+        };
+        anyhow::ensure!(min <= max, "{:?}: minimum is greater than maximum", value);
+        Ok(Delay::Uniform { min, max })
+    }
+}
+
+/// The sleeps after pattern splits, from --split-sleep-ms and --max-splits. The n-th split of a
+/// connection sleeps for the n-th delay, and the last delay repeats for all further splits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SleepSchedule {
+    delays: Vec<Delay>,
+    max_splits: Option<usize>,
+}
+
+impl SleepSchedule {
+    pub fn new(delays: Vec<Delay>, max_splits: Option<usize>) -> Result<Self, Error> {
+        anyhow::ensure!(!delays.is_empty(), "--split-sleep-ms must not be empty");
+        Ok(SleepSchedule { delays, max_splits })
+    }
+
+    /// The sleep after split number index of a connection, counting from 0, or None if the
+    /// connection has reached --max-splits and should not be split anymore.
+    fn sleep_ms(&self, index: usize) -> Option<u64> {
+        if self.max_splits.is_some_and(|max| index >= max) {
+            return None;
+        }
+        let delay = self
+            .delays
+            .get(index)
+            .unwrap_or(self.delays.last().unwrap());
+        Some(delay.sample())
+    }
+}
+
+/// --random-chunks: split the first bytes of the stream into chunks of random size, without any
+/// pattern.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RandomChunks {
+    bytes: usize,
+    min: usize,
+    max: usize,
+    delay: Delay,
+}
+
+impl RandomChunks {
+    pub fn new(bytes: usize, min: usize, max: usize, delay: Delay) -> Result<Self, Error> {
+        anyhow::ensure!(min > 0, "--min-chunk must be at least 1");
+        anyhow::ensure!(min <= max, "--min-chunk is greater than --max-chunk");
+        Ok(RandomChunks {
+            bytes,
+            min,
+            max,
+            delay,
+        })
+    }
+}
+
+/// Cuts the outbound stream of one connection into random chunks, across reads.
+struct Chunker<'a> {
+    chunks: &'a RandomChunks,
+    /// Bytes of the stream that are still to be chunked.
+    remaining: usize,
+    /// Bytes until the end of the current chunk.
+    chunk_left: usize,
+}
+
+impl<'a> Chunker<'a> {
+    fn new(chunks: &'a RandomChunks) -> Self {
+        Chunker {
+            chunks,
+            remaining: chunks.bytes,
+            chunk_left: 0,
+        }
+    }
+
+    /// Feed the next chunk of the stream, and return where to split it, in ascending order.
+    fn find_splits(&mut self, buf: &[u8]) -> Vec<Split> {
+        let mut splits = Vec::new();
+        let mut pos = 0;
+
+        while self.remaining > 0 && pos < buf.len() {
+            if self.chunk_left == 0 {
+                self.chunk_left = rand::thread_rng()
+                    .gen_range(self.chunks.min..=self.chunks.max)
+                    .min(self.remaining);
+            }
+
+            let taken = self.chunk_left.min(buf.len() - pos);
+            pos += taken;
+            self.chunk_left -= taken;
+            self.remaining -= taken;
+
+            if self.chunk_left == 0 {
+                splits.push((pos, self.chunks.delay.sample()));
+            }
+        }
+
+        splits
+    }
+}
+
+/// The most memory that compiling --split-after-regex may take, in bytes.
+const MAX_REGEX_SIZE: usize = 10 << 20;
+
+/// Everything that decides where to split the outbound stream: the compiled --split-after and
+/// --split-after-regex patterns, the --split-sni and --tls-record-split points, the random
+/// chunks and the --host-prefix decoy. Shared by all connections.
+pub struct SplitPatterns {
+    literals: Option<aho_corasick::dfa::DFA>,
+    regexes: Option<dense::DFA<Vec<u32>>>,
+    sni: Vec<SniSplit>,
+    records: Vec<RecordSplit>,
+    chunks: Option<RandomChunks>,
+    host_prefix: Option<String>,
+}
+
+impl SplitPatterns {
+    pub fn new(
+        literals: &[String],
+        regexes: &[String],
+        sni: &[SniSplit],
+        records: &[RecordSplit],
+        chunks: Option<RandomChunks>,
+        host_prefix: Option<String>,
+    ) -> Result<Self, Error> {
+        let literals = if literals.is_empty() {
+            None
+        } else {
+            anyhow::ensure!(
+                literals.iter().all(|literal| !literal.is_empty()),
+                "--split-after must not be empty"
+            );
+            Some(aho_corasick::dfa::DFA::new(literals).context("invalid --split-after")?)
+        };
+
+        let regexes = if regexes.is_empty() {
+            None
+        } else {
+            // match on raw bytes, the stream does not have to be UTF-8
+            let dfa = dense::Builder::new()
+                .configure(
+                    dense::Config::new()
+                        .dfa_size_limit(Some(MAX_REGEX_SIZE))
+                        .determinize_size_limit(Some(MAX_REGEX_SIZE)),
+                )
+                .syntax(syntax::Config::new().unicode(false).utf8(false))
+                .thompson(regex_automata::nfa::thompson::Config::new().utf8(false))
+                .build_many(regexes)
+                .context("invalid --split-after-regex")?;
+            let start = dfa.start_state(&start::Config::new())?;
+            anyhow::ensure!(
+                !dfa.is_match_state(dfa.next_eoi_state(start)),
+                "--split-after-regex must not match the empty string"
+            );
+            Some(dfa)
+        };
+
+        Ok(SplitPatterns {
+            literals,
+            regexes,
+            sni: sni.to_vec(),
+            records: records.to_vec(),
+            chunks,
+            host_prefix,
+        })
+    }
+}
+
+/// Searches the outbound stream of one connection for split patterns. Matches are found across
+/// reads, and the search starts over after every split.
+struct Splitter<'a> {
+    patterns: &'a SplitPatterns,
+    literal_state: aho_corasick::automaton::StateID,
+    regex_state: regex_automata::util::primitives::StateID,
+}
+
+impl<'a> Splitter<'a> {
+    fn new(patterns: &'a SplitPatterns) -> Self {
+        let mut splitter = Splitter {
+            patterns,
+            literal_state: Default::default(),
+            regex_state: Default::default(),
+        };
+        splitter.reset();
+        splitter
+    }
+
+    fn reset(&mut self) {
+        if let Some(ref literals) = self.patterns.literals {
+            self.literal_state = literals.start_state(Anchored::No).unwrap();
+        }
+        if let Some(ref regexes) = self.patterns.regexes {
+            // checked in SplitPatterns::new
+            self.regex_state = regexes.start_state(&start::Config::new()).unwrap();
+        }
+    }
+
+    /// Feed the next chunk of the stream, and return the offsets into it after which to split,
+    /// in ascending order.
+    fn find_splits(&mut self, chunk: &[u8]) -> Vec<usize> {
+        let mut splits = Vec::new();
+
+        for (i, &byte) in chunk.iter().enumerate() {
+            if let Some(ref regexes) = self.patterns.regexes {
+                // the DFA reports matches one byte late, a match state here means that a match
+                // ended right before this byte
+                self.regex_state = regexes.next_state(self.regex_state, byte);
+                if regexes.is_match_state(self.regex_state) {
+                    splits.push(i);
+                    self.reset();
+                    self.regex_state = regexes.next_state(self.regex_state, byte);
+                }
+            }
+
+            if let Some(ref literals) = self.patterns.literals {
+                self.literal_state = literals.next_state(Anchored::No, self.literal_state, byte);
+                if literals.is_match(self.literal_state) {
+                    splits.push(i + 1);
+                    self.reset();
+                }
+            }
+        }
+
+        // a regex match that ends exactly at the end of the chunk is only found with the first
+        // byte of the next one, at offset 0. The end of a read is not the end of the stream, so
+        // checking for end of input here would make $, \b and \z match wherever a read ends.
+
+        splits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fmt, io,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use tokio::io::{join, ReadBuf};
+    use tracing_test::traced_test;
+
+    use super::*;
+    use crate::client_hello::tests::build_client_hello;
+
+    /// An AsyncRead that always returns pending
+    struct Nothing;
+
+    impl AsyncRead for Nothing {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    /// An AsyncWrite that makes each individual write, and how they are fragmented, visible for
+    /// inspection.
+    #[derive(Default)]
+    struct Fragments(Vec<Vec<u8>>);
+
+    impl PartialEq<Vec<Vec<u8>>> for Fragments {
+        fn eq(&self, other: &Vec<Vec<u8>>) -> bool {
+            self.0 == *other
+        }
+    }
+
+    impl fmt::Debug for Fragments {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+            writeln!(f, "Fragments([")?;
+            for fragment in &self.0 {
+                writeln!(f, "  {:?},", String::from_utf8_lossy(fragment))?;
+            }
+
+            writeln!(f, "])")
+        }
+    }
+
+    impl AsyncRead for Fragments {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if let Some(next) = self.0.first_mut() {
+                assert!(!next.is_empty());
+                let mut next2 = next.as_slice();
+                let rv = std::pin::pin!(&mut next2).poll_read(cx, buf);
+                if next2.is_empty() {
+                    self.0.remove(0);
+                } else {
+                    *next = next2.to_vec();
+                }
+                assert!(matches!(rv, Poll::Ready(Ok(()))));
+                return rv;
+            }
+
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for Fragments {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.0.push(buf.to_vec());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl FragmentWrite for Fragments {}
+
+    fn no_sleep() -> SleepSchedule {
+        SleepSchedule::new(vec!["0".parse().unwrap()], None).unwrap()
+    }
+
+    fn patterns(literals: &[&str], regexes: &[&str]) -> SplitPatterns {
+        let literals = literals.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let regexes = regexes.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        SplitPatterns::new(&literals, &regexes, &[], &[], None, None).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_split_begin() {
+        let mut downloaded = Vec::new();
+        let mut client = join(b"www.speedtest.net.example.com".as_slice(), &mut downloaded);
+
+        let mut uploaded = Fragments::default();
+        let mut server = join(Nothing, &mut uploaded);
+
+        process_connection(
+            &mut client,
+            &mut server,
+            &patterns(&["www.speedtest.net"], &[]),
+            &no_sleep(),
+            Direction::Up,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(downloaded, b"");
+        assert_eq!(
+            uploaded,
+            vec![b"www.speedtest.net".to_vec(), b".example.com".to_vec(),]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_split_middle() {
+        let mut downloaded = Vec::new();
+        let mut client = join(
+            b"Host: www.speedtest.net.example.com".as_slice(),
+            &mut downloaded,
+        );
+
+        let mut uploaded = Fragments::default();
+        let mut server = join(Nothing, &mut uploaded);
+
+        process_connection(
+            &mut client,
+            &mut server,
+            &patterns(&["www.speedtest.net"], &[]),
+            &no_sleep(),
+            Direction::Up,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(downloaded, b"");
+        assert_eq!(
+            uploaded,
+            vec![
+                b"Host: www.speedtest.net".to_vec(),
+                b".example.com".to_vec(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_split_partial_end() {
+        let mut downloaded = Vec::new();
+        let mut client = join(b"Host: www.speedtes".as_slice(), &mut downloaded);
+
+        let mut uploaded = Fragments::default();
+        let mut server = join(Nothing, &mut uploaded);
+
+        let sleep_count = process_connection(
+            &mut client,
+            &mut server,
+            &patterns(&["www.speedtest.net"], &[]),
+            &no_sleep(),
+            Direction::Up,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(downloaded, b"");
+        assert_eq!(uploaded, vec![b"Host: www.speedtes".to_vec(),]);
+        assert_eq!(sleep_count, 0);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_split_partial_end2() {
+        let mut downloaded = Vec::new();
+        let mut client = join(
+            Fragments(vec![
+                b"Host: www.speedtes".to_vec(),
+                b"t.net.example.com".to_vec(),
+            ]),
+            &mut downloaded,
+        );
+
+        let mut uploaded = Fragments::default();
+        let mut server = join(Nothing, &mut uploaded);
+
+        let sleep_count = process_connection(
+            &mut client,
+            &mut server,
+            &patterns(&["www.speedtest.net"], &[]),
+            &no_sleep(),
+            Direction::Up,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(downloaded, b"");
+        assert_eq!(
+            uploaded,
+            vec![
+                b"Host: www.speedtes".to_vec(),
+                b"t.net".to_vec(),
+                b".example.com".to_vec(),
+            ]
+        );
+        assert_eq!(sleep_count, 1);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_split_three() {
+        let mut downloaded = Vec::new();
+        let mut client = join(
+            Fragments(vec![
+                b"Host: www.".to_vec(),
+                b"speedtes".to_vec(),
+                b"t.net.example.com".to_vec(),
+            ]),
+            &mut downloaded,
+        );
+
+        let mut uploaded = Fragments::default();
+        let mut server = join(Nothing, &mut uploaded);
+
+        let sleep_count = process_connection(
+            &mut client,
+            &mut server,
+            &patterns(&["www.speedtest.net"], &[]),
+            &no_sleep(),
+            Direction::Up,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(downloaded, b"");
+        assert_eq!(
+            uploaded,
+            vec![
+                b"Host: www.".to_vec(),
+                b"speedtes".to_vec(),
+                b"t.net".to_vec(),
+                b".example.com".to_vec(),
+            ]
+        );
+        assert_eq!(sleep_count, 1);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn regression_split_many() {
+        let mut downloaded = Vec::new();
+        let mut client = join(
+            Fragments(vec![
+                b"Host: www.".to_vec(),
+                b"speedtes".to_vec(),
+                b"hello".to_vec(),
+                b"t.net.example.com".to_vec(),
+            ]),
+            &mut downloaded,
+        );
+
+        let mut uploaded = Fragments::default();
+        let mut server = join(Nothing, &mut uploaded);
+
+        let sleep_count = process_connection(
+            &mut client,
+            &mut server,
+            &patterns(&["www.speedtest.net"], &[]),
+            &no_sleep(),
+            Direction::Up,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(downloaded, b"");
+        assert_eq!(
+            uploaded,
+            vec![
+                b"Host: www.".to_vec(),
+                b"speedtes".to_vec(),
+                b"hello".to_vec(),
+                b"t.net.example.com".to_vec(),
+            ]
+        );
+        assert_eq!(sleep_count, 0);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn regression_split_series() {
+        let mut downloaded = Vec::new();
+        let mut client = join(
+            Fragments(vec![
+                b"Host: www.".to_vec(),
+                b"speedtest.nethellowww.".to_vec(),
+                b"speedtest.net.example.com".to_vec(),
+            ]),
+            &mut downloaded,
+        );
+
+        let mut uploaded = Fragments::default();
+        let mut server = join(Nothing, &mut uploaded);
+
+        let sleep_count = process_connection(
+            &mut client,
+            &mut server,
+            &patterns(&["www.speedtest.net"], &[]),
+            &no_sleep(),
+            Direction::Up,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(downloaded, b"");
+        assert_eq!(
+            uploaded,
+            vec![
+                b"Host: www.".to_vec(),
+                b"speedtest.net".to_vec(),
+                b"hellowww.".to_vec(),
+                b"speedtest.net".to_vec(),
+                b".example.com".to_vec(),
+            ]
+        );
+        assert_eq!(sleep_count, 2);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_split_multiple() {
+        let mut downloaded = Vec::new();
+        let mut client = join(
+            Fragments(vec![
+                b"GET / HTTP/1.1\r\nHost: www.spe".to_vec(),
+                b"edtest.net\r\nUser-Agent: foo\r\n".to_vec(),
+            ]),
+            &mut downloaded,
+        );
+
+        let mut uploaded = Fragments::default();
+        let mut server = join(Nothing, &mut uploaded);
+
+        let sleep_count = process_connection(
+            &mut client,
+            &mut server,
+            &patterns(&["speedtest", "Host: ", "User-Agent"], &[]),
+            &no_sleep(),
+            Direction::Up,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(downloaded, b"");
+        assert_eq!(
+            uploaded,
+            vec![
+                b"GET / HTTP/1.1\r\nHost: ".to_vec(),
+                b"www.spe".to_vec(),
+                b"edtest".to_vec(),
+                b".net\r\nUser-Agent".to_vec(),
+                b": foo\r\n".to_vec(),
+            ]
+        );
+        assert_eq!(sleep_count, 3);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_split_regex() {
+        let mut downloaded = Vec::new();
+        let mut client = join(
+            Fragments(vec![
+                b"GET / HTTP/1.1\r\nhost: w".to_vec(),
+                b"ww.speedtest.net\r\n\r\n".to_vec(),
+                b"GET / HTTP/1.1\r\nHOST: abc".to_vec(),
+                b"\r\n\r\n".to_vec(),
+            ]),
+            &mut downloaded,
+        );
+
+        let mut uploaded = Fragments::default();
+        let mut server = join(Nothing, &mut uploaded);
+
+        let sleep_count = process_connection(
+            &mut client,
+            &mut server,
+            &patterns(&[], &[r"(?i)host: \S{3}"]),
+            &no_sleep(),
+            Direction::Up,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(downloaded, b"");
+        assert_eq!(
+            uploaded,
+            vec![
+                b"GET / HTTP/1.1\r\nhost: w".to_vec(),
+                b"ww".to_vec(),
+                b".speedtest.net\r\n\r\n".to_vec(),
+                // the match ends at the end of the read
+                b"GET / HTTP/1.1\r\nHOST: abc".to_vec(),
+                b"\r\n\r\n".to_vec(),
+            ]
+        );
+        assert_eq!(sleep_count, 2);
+    }
+
+    #[test]
+    fn test_split_regex_read_boundaries() {
+        let patterns = patterns(&[], &[r"host: \w+\b", r"abc$"]);
+        let stream = b"host: www.speedtest.net abc";
+
+        // the end of a read is neither a word boundary nor the end of the stream
+        for at in 0..stream.len() {
+            let mut splitter = Splitter::new(&patterns);
+            let (first, second) = stream.split_at(at);
+            let splits = splitter
+                .find_splits(first)
+                .into_iter()
+                .chain(splitter.find_splits(second).into_iter().map(|i| i + at))
+                .collect::<Vec<_>>();
+            assert_eq!(splits, vec![9], "read boundary at {}", at);
+        }
+    }
+
+    #[test]
+    fn test_regex_size_limit() {
+        let err = SplitPatterns::new(&[], &[r"[01]*1[01]{24}".to_owned()], &[], &[], None, None)
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "invalid --split-after-regex");
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_split_regex_and_literal() {
+        let mut downloaded = Vec::new();
+        let mut client = join(b"\x16\x03\x01hello world".as_slice(), &mut downloaded);
+
+        let mut uploaded = Fragments::default();
+        let mut server = join(Nothing, &mut uploaded);
+
+        let sleep_count = process_connection(
+            &mut client,
+            &mut server,
+            &patterns(&["hello"], &[r"\x16\x03[\x00-\x04]"]),
+            &no_sleep(),
+            Direction::Up,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(downloaded, b"");
+        assert_eq!(
+            uploaded,
+            vec![
+                b"\x16\x03\x01".to_vec(),
+                b"hello".to_vec(),
+                b" world".to_vec(),
+            ]
+        );
+        assert_eq!(sleep_count, 2);
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        assert!(SplitPatterns::new(&[], &["(".to_owned()], &[], &[], None, None).is_err());
+        assert!(SplitPatterns::new(&[], &["a*".to_owned()], &[], &[], None, None).is_err());
+        assert!(SplitPatterns::new(&["".to_owned()], &[], &[], &[], None, None).is_err());
     }
 
     #[tokio::test]
     #[traced_test]
-    async fn regression_split_many() {
+    async fn test_split_sni() {
+        let record = build_client_hello(Some("www.example.com"), &[]);
+        let hello = ClientHello::parse(&record).unwrap();
+        let name = hello.server_name.unwrap();
+        let extension = hello.server_name_extension.unwrap();
+        assert_eq!(&record[name.clone()], b"www.example.com");
+
         let mut downloaded = Vec::new();
         let mut client = join(
             Fragments(vec![
-                b"Host: www.".to_vec(),
-                b"speedtes".to_vec(),
-                b"hello".to_vec(),
-                b"t.net.example.com".to_vec(),
+                record[..10].to_vec(),
+                record[10..].to_vec(),
+                b"GET /".to_vec(),
             ]),
             &mut downloaded,
         );
@@ -370,32 +1751,479 @@ mod tests {
         let mut uploaded = Fragments::default();
         let mut server = join(Nothing, &mut uploaded);
 
-        let sleep_count = process_connection(&mut client, &mut server, b"www.speedtest.net", 0)
-            .await
-            .unwrap();
+        let patterns = SplitPatterns::new(
+            &[],
+            &[],
+            &[SniSplit::End, SniSplit::Offset(4), SniSplit::Middle],
+            &[],
+            None,
+            None,
+        )
+        .unwrap();
+        let sleep_count = process_connection(
+            &mut client,
+            &mut server,
+            &patterns,
+            &no_sleep(),
+            Direction::Up,
+            None,
+        )
+        .await
+        .unwrap();
 
         assert_eq!(downloaded, b"");
         assert_eq!(
             uploaded,
             vec![
-                b"Host: www.".to_vec(),
-                b"speedtes".to_vec(),
-                b"hello".to_vec(),
-                b"t.net.example.com".to_vec(),
+                record[..name.start + 4].to_vec(),
+                b"exa".to_vec(),
+                record[name.start + 7..extension.end].to_vec(),
+                record[extension.end..].to_vec(),
+                b"GET /".to_vec(),
+            ]
+        );
+        assert_eq!(sleep_count, 3);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_split_sni_and_literal() {
+        let record = build_client_hello(Some("www.example.com"), &["h2"]);
+        let hello = ClientHello::parse(&record).unwrap();
+        let name = hello.server_name.unwrap();
+
+        let mut downloaded = Vec::new();
+        let mut client = join(record.as_slice(), &mut downloaded);
+
+        let mut uploaded = Fragments::default();
+        let mut server = join(Nothing, &mut uploaded);
+
+        let patterns = SplitPatterns::new(
+            &["h2".to_owned()],
+            &[],
+            &[SniSplit::Middle],
+            &[],
+            None,
+            None,
+        )
+        .unwrap();
+        let sleep_count = process_connection(
+            &mut client,
+            &mut server,
+            &patterns,
+            &no_sleep(),
+            Direction::Up,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let alpn_end = record.windows(2).position(|w| w == b"h2").unwrap() + 2;
+        assert_eq!(
+            uploaded,
+            vec![
+                record[..name.start + 7].to_vec(),
+                record[name.start + 7..alpn_end].to_vec(),
+                record[alpn_end..].to_vec(),
             ]
         );
+        assert_eq!(sleep_count, 2);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_split_sni_no_tls() {
+        let mut downloaded = Vec::new();
+        let mut client = join(b"GET / HTTP/1.1\r\n".as_slice(), &mut downloaded);
+
+        let mut uploaded = Fragments::default();
+        let mut server = join(Nothing, &mut uploaded);
+
+        let patterns = SplitPatterns::new(&[], &[], &[SniSplit::Middle], &[], None, None).unwrap();
+        let sleep_count = process_connection(
+            &mut client,
+            &mut server,
+            &patterns,
+            &no_sleep(),
+            Direction::Up,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(uploaded, vec![b"GET / HTTP/1.1\r\n".to_vec()]);
         assert_eq!(sleep_count, 0);
+
+        // no SNI
+        let record = build_client_hello(None, &[]);
+        assert_eq!(
+            sni_splits(&record, &[SniSplit::Middle]),
+            Vec::<usize>::new()
+        );
     }
 
     #[tokio::test]
     #[traced_test]
-    async fn regression_split_series() {
+    async fn test_random_chunks() {
+        let mut downloaded = Vec::new();
+        let mut client = join(
+            Fragments(vec![b"GET / HT".to_vec(), b"TP/1.1\r\n".to_vec()]),
+            &mut downloaded,
+        );
+
+        let mut uploaded = Fragments::default();
+        let mut server = join(Nothing, &mut uploaded);
+
+        // with min == max, the chunks are not random at all
+        let chunks = RandomChunks::new(10, 3, 3, "0".parse().unwrap()).unwrap();
+        let patterns =
+            SplitPatterns::new(&["1.1".to_owned()], &[], &[], &[], Some(chunks), None).unwrap();
+        let sleep_count = process_connection(
+            &mut client,
+            &mut server,
+            &patterns,
+            &no_sleep(),
+            Direction::Up,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(downloaded, b"");
+        assert_eq!(
+            uploaded,
+            vec![
+                b"GET".to_vec(),
+                b" / ".to_vec(),
+                b"HT".to_vec(),
+                b"T".to_vec(),
+                b"P".to_vec(),
+                b"/1.1".to_vec(),
+                b"\r\n".to_vec(),
+            ]
+        );
+        assert_eq!(sleep_count, 5);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_random_chunks_down() {
+        let mut downloaded = Fragments::default();
+        let mut client = join(Nothing, &mut downloaded);
+
+        let mut uploaded = Fragments::default();
+        let mut server = join(Fragments(vec![b"HTTP/1.1 200".to_vec()]), &mut uploaded);
+
+        let chunks = RandomChunks::new(6, 3, 3, "0".parse().unwrap()).unwrap();
+        let patterns = SplitPatterns::new(&[], &[], &[], &[], Some(chunks), None).unwrap();
+        let sleep_count = process_connection(
+            &mut client,
+            &mut server,
+            &patterns,
+            &no_sleep(),
+            Direction::Down,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(uploaded, vec![]);
+        assert_eq!(
+            downloaded,
+            vec![b"HTT".to_vec(), b"P/1".to_vec(), b".1 200".to_vec()]
+        );
+        assert_eq!(sleep_count, 2);
+    }
+
+    #[test]
+    fn test_random_chunk_sizes() {
+        let chunks = RandomChunks::new(1000, 2, 5, "1-3".parse().unwrap()).unwrap();
+        let mut chunker = Chunker::new(&chunks);
+        let mut splits = chunker.find_splits(&[0; 600]);
+        splits.extend(
+            chunker
+                .find_splits(&[0; 600])
+                .into_iter()
+                .map(|(offset, sleep_ms)| (offset + 600, sleep_ms)),
+        );
+
+        let mut start = 0;
+        for &(end, sleep_ms) in &splits {
+            // only the last chunk may be smaller
+            assert!(end - start <= 5);
+            assert!(end - start >= 2 || end == 1000);
+            assert!((1..=3).contains(&sleep_ms));
+            start = end;
+        }
+        assert_eq!(start, 1000);
+
+        assert!(RandomChunks::new(10, 0, 3, "0".parse().unwrap()).is_err());
+        assert!(RandomChunks::new(10, 4, 3, "0".parse().unwrap()).is_err());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_max_splits() {
+        let mut downloaded = Vec::new();
+        let mut client = join(
+            Fragments(vec![b"a.a.".to_vec(), b"a.a.".to_vec()]),
+            &mut downloaded,
+        );
+
+        let mut uploaded = Fragments::default();
+        let mut server = join(Nothing, &mut uploaded);
+
+        let sleeps = SleepSchedule::new(vec!["0".parse().unwrap()], Some(3)).unwrap();
+        let sleep_count = process_connection(
+            &mut client,
+            &mut server,
+            &patterns(&["a"], &[]),
+            &sleeps,
+            Direction::Up,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(downloaded, b"");
+        assert_eq!(
+            uploaded,
+            vec![
+                b"a".to_vec(),
+                b".a".to_vec(),
+                b".".to_vec(),
+                b"a".to_vec(),
+                b".a.".to_vec(),
+            ]
+        );
+        assert_eq!(sleep_count, 3);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_direction_down() {
+        let patterns = patterns(&["www.speedtest.net"], &[]);
+
+        for direction in [Direction::Down, Direction::Both] {
+            let mut downloaded = Fragments::default();
+            let mut client = join(Nothing, &mut downloaded);
+
+            let mut uploaded = Fragments::default();
+            let mut server = join(
+                Fragments(vec![
+                    b"HTTP/1.1 301\r\nLocation: https://www.speed".to_vec(),
+                    b"test.net/\r\n\r\n".to_vec(),
+                ]),
+                &mut uploaded,
+            );
+
+            let sleep_count = process_connection(
+                &mut client,
+                &mut server,
+                &patterns,
+                &no_sleep(),
+                direction,
+                None,
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(uploaded, vec![]);
+            assert_eq!(
+                downloaded,
+                vec![
+                    b"HTTP/1.1 301\r\nLocation: https://www.speed".to_vec(),
+                    b"test.net".to_vec(),
+                    b"/\r\n\r\n".to_vec(),
+                ]
+            );
+            assert_eq!(sleep_count, 1);
+        }
+
+        // the client's data is only split with up or both
+        for (direction, expected) in [(Direction::Down, 0), (Direction::Both, 1)] {
+            let mut downloaded = Fragments::default();
+            let mut client = join(b"Host: www.speedtest.net.".as_slice(), &mut downloaded);
+
+            let mut uploaded = Fragments::default();
+            let mut server = join(Nothing, &mut uploaded);
+
+            let sleep_count = process_connection(
+                &mut client,
+                &mut server,
+                &patterns,
+                &no_sleep(),
+                direction,
+                None,
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(uploaded.0.concat(), b"Host: www.speedtest.net.");
+            assert_eq!(uploaded.0.len(), expected + 1);
+            assert_eq!(sleep_count, expected);
+        }
+    }
+
+    #[test]
+    fn test_sleep_schedule() {
+        let delays = "5000,200-300,0"
+            .split(',')
+            .map(|delay| delay.parse().unwrap())
+            .collect();
+        let sleeps = SleepSchedule::new(delays, Some(4)).unwrap();
+
+        assert_eq!(sleeps.sleep_ms(0), Some(5000));
+        assert!((200..=300).contains(&sleeps.sleep_ms(1).unwrap()));
+        assert_eq!(sleeps.sleep_ms(2), Some(0));
+        assert_eq!(sleeps.sleep_ms(3), Some(0));
+        assert_eq!(sleeps.sleep_ms(4), None);
+
+        assert!(SleepSchedule::new(Vec::new(), None).is_err());
+    }
+
+    #[test]
+    fn test_parse_delay() {
+        assert_eq!(
+            "5".parse::<Delay>().unwrap(),
+            Delay::Uniform { min: 5, max: 5 }
+        );
+        assert_eq!(
+            "10-50".parse::<Delay>().unwrap(),
+            Delay::Uniform { min: 10, max: 50 }
+        );
+        assert_eq!(
+            "100~20".parse::<Delay>().unwrap(),
+            Delay::Normal {
+                mean: 100.0,
+                std_dev: 20.0
+            }
+        );
+        assert!("50-10".parse::<Delay>().is_err());
+        assert!("-5".parse::<Delay>().is_err());
+        assert!("100~".parse::<Delay>().is_err());
+    }
+
+    #[test]
+    fn test_delay_sample() {
+        let uniform = Delay::Uniform { min: 10, max: 20 };
+        let normal = Delay::Normal {
+            mean: 100.0,
+            std_dev: 10.0,
+        };
+        let samples = (0..1000).map(|_| normal.sample()).collect::<Vec<_>>();
+        for _ in 0..1000 {
+            assert!((10..=20).contains(&uniform.sample()));
+        }
+        let mean = samples.iter().sum::<u64>() as f64 / samples.len() as f64;
+        assert!((95.0..105.0).contains(&mean), "mean {}", mean);
+        assert!(samples.iter().any(|&ms| ms != samples[0]));
+
+        let below_zero = Delay::Normal {
+            mean: 0.0,
+            std_dev: 100.0,
+        };
+        assert!((0..1000).any(|_| below_zero.sample() == 0));
+    }
+
+    /// The number of segments with data that the kernel has sent on stream.
+    #[cfg(target_os = "linux")]
+    fn data_segments_sent(stream: &TcpStream) -> u32 {
+        use std::os::fd::AsRawFd;
+
+        let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+        let rv = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                &mut info as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(rv, 0);
+        info.tcpi_data_segs_out
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    #[traced_test]
+    async fn test_segments_without_sleep() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        stream.set_nodelay(true).unwrap();
+        let (mut socket, _) = listener.accept().await.unwrap();
+
+        let mut upstream = SegmentedStream::new(stream, Vec::new());
+        let buf = b"Host: www.speedtest.net.example.com";
+        let sleep_count = write_fragments(&mut upstream, buf, &[(10, 0), (23, 0)], &mut None)
+            .await
+            .unwrap();
+        upstream.flush().await.unwrap();
+        assert_eq!(sleep_count, 2);
+        assert_eq!(data_segments_sent(&upstream.inner), 3);
+
+        let mut received = vec![0; buf.len()];
+        socket.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, buf);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    #[traced_test]
+    async fn test_desync_oob() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        stream.set_nodelay(true).unwrap();
+        let (mut socket, _) = listener.accept().await.unwrap();
+
+        let mut upstream = SegmentedStream::new(stream, Vec::new());
+        let buf = b"Host: www.speedtest.net.example.com";
+        let mut desync = Some(Desync::Oob);
+        let sleep_count = write_fragments(&mut upstream, buf, &[(10, 0), (23, 0)], &mut desync)
+            .await
+            .unwrap();
+        upstream.flush().await.unwrap();
+        upstream.inner.shutdown().await.unwrap();
+        assert_eq!(sleep_count, 2);
+        assert_eq!(desync, None);
+        // the urgent byte is a segment of its own, and only sent at the first split
+        assert_eq!(data_segments_sent(&upstream.inner), 4);
+
+        // the server's kernel removes the urgent byte from the stream
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, buf);
+    }
+
+    /// Split a buffer of TLS records into (content type, payload) pairs.
+    fn parse_records(mut buf: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut records = Vec::new();
+        while !buf.is_empty() {
+            let len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+            records.push((buf[0], buf[5..5 + len].to_vec()));
+            buf = &buf[5 + len..];
+        }
+        records
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_tls_record_split() {
+        let record = build_client_hello(Some("www.example.com"), &[]);
+        let name = ClientHello::parse(&record).unwrap().server_name.unwrap();
+
         let mut downloaded = Vec::new();
         let mut client = join(
             Fragments(vec![
-                b"Host: www.".to_vec(),
-                b"speedtest.nethellowww.".to_vec(),
-                b"speedtest.net.example.com".to_vec(),
+                record[..10].to_vec(),
+                record[10..].to_vec(),
+                b"GET /".to_vec(),
             ]),
             &mut downloaded,
         );
@@ -403,21 +2231,355 @@ mod tests {
         let mut uploaded = Fragments::default();
         let mut server = join(Nothing, &mut uploaded);
 
-        let sleep_count = process_connection(&mut client, &mut server, b"www.speedtest.net", 0)
-            .await
+        let patterns = SplitPatterns::new(
+            &[],
+            &[],
+            &[],
+            &[RecordSplit::Sni(SniSplit::Middle), RecordSplit::Offset(1)],
+            None,
+            None,
+        )
+        .unwrap();
+        let sleep_count = process_connection(
+            &mut client,
+            &mut server,
+            &patterns,
+            &no_sleep(),
+            Direction::Up,
+            None,
+        )
+        .await
+        .unwrap();
+
+        // the records go out in one write
+        assert_eq!(uploaded.0.len(), 2);
+        assert_eq!(uploaded.0[1], b"GET /");
+        assert_eq!(sleep_count, 0);
+        assert_eq!(
+            parse_records(&uploaded.0[0]),
+            vec![
+                (0x16, record[5..6].to_vec()),
+                (0x16, record[6..name.start + 7].to_vec()),
+                (0x16, record[name.start + 7..].to_vec()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_tls_record_split_and_sni() {
+        let record = build_client_hello(Some("www.example.com"), &[]);
+        let hello = ClientHello::parse(&record).unwrap();
+        let name = hello.server_name.unwrap();
+        let extension = hello.server_name_extension.unwrap();
+
+        let mut downloaded = Vec::new();
+        let mut client = join(record.as_slice(), &mut downloaded);
+
+        let mut uploaded = Fragments::default();
+        let mut server = join(Nothing, &mut uploaded);
+
+        let patterns = SplitPatterns::new(
+            &[],
+            &[],
+            &[SniSplit::Middle, SniSplit::End],
+            &["sni:middle".parse().unwrap()],
+            None,
+            None,
+        )
+        .unwrap();
+        let sleep_count = process_connection(
+            &mut client,
+            &mut server,
+            &patterns,
+            &no_sleep(),
+            Direction::Up,
+            None,
+        )
+        .await
+        .unwrap();
+
+        // the TCP split at the end of the first record comes before the header of the second
+        let mut first = record[..3].to_vec();
+        first.extend_from_slice(&((name.start + 7 - 5) as u16).to_be_bytes());
+        first.extend_from_slice(&record[5..name.start + 7]);
+        assert_eq!(uploaded.0.len(), 3);
+        assert_eq!(uploaded.0[0], first);
+        assert_eq!(
+            parse_records(&[&uploaded.0[1][..], &uploaded.0[2][..]].concat()),
+            vec![(0x16, record[name.start + 7..].to_vec())]
+        );
+        assert_eq!(uploaded.0[2], record[extension.end..].to_vec(),);
+        assert_eq!(sleep_count, 2);
+
+        // random splits in two records, anything that is not a handshake record is untouched
+        let mut splits = Vec::new();
+        let split = split_record(&record, &[RecordSplit::Random], &mut splits);
+        assert_eq!(parse_records(&split).len(), 2);
+        assert_eq!(
+            split_record(b"GET /", &[RecordSplit::Offset(1)], &mut splits),
+            b"GET /"
+        );
+    }
+
+    #[test]
+    fn test_tls_record_split_tcp_offsets() {
+        let record = build_client_hello(Some("www.example.com"), &[]);
+        let first = [&record[..], b"after"].concat();
+
+        // a TCP split in the second record, and one in the data after the ClientHello
+        let mut splits = vec![RECORD_HEADER_LEN + 3, record.len() + 2];
+        let split = split_record(&first, &[RecordSplit::Offset(1)], &mut splits);
+
+        assert_eq!(split.len(), first.len() + RECORD_HEADER_LEN);
+        assert_eq!(
+            split[splits[0]..],
+            [&record[RECORD_HEADER_LEN + 3..], b"after"].concat()
+        );
+        assert_eq!(&split[splits[1]..], b"ter");
+    }
+
+    #[test]
+    fn test_parse_record_split() {
+        assert_eq!("3".parse::<RecordSplit>().unwrap(), RecordSplit::Offset(3));
+        assert_eq!(
+            "random".parse::<RecordSplit>().unwrap(),
+            RecordSplit::Random
+        );
+        assert_eq!(
+            "sni".parse::<RecordSplit>().unwrap(),
+            RecordSplit::Sni(SniSplit::Middle)
+        );
+        assert_eq!(
+            "sni:end".parse::<RecordSplit>().unwrap(),
+            RecordSplit::Sni(SniSplit::End)
+        );
+        assert!("sni:start".parse::<RecordSplit>().is_err());
+        assert!("end".parse::<RecordSplit>().is_err());
+    }
+
+    #[test]
+    fn test_route_matches() {
+        let route = ".speedtest.net.example.com=www.speedtest.net:443"
+            .parse::<Route>()
             .unwrap();
+        assert!(route.matches("a.speedtest.net.example.com"));
+        assert!(route.matches("www.SpeedTest.net.example.com."));
+        assert!(!route.matches("speedtest.net.example.com"));
+        assert!(!route.matches("www.speedtest.net"));
 
-        assert_eq!(downloaded, b"");
+        let route = "Example.com=127.0.0.1:80".parse::<Route>().unwrap();
+        assert!(route.matches("example.com"));
+        assert!(!route.matches("www.example.com"));
+
+        assert!("example.com".parse::<Route>().is_err());
+        assert!("=127.0.0.1:80".parse::<Route>().is_err());
+    }
+
+    #[test]
+    fn test_host_header() {
+        let host = |request: &[u8]| host_header(request);
+        assert_eq!(
+            host(b"GET / HTTP/1.1\r\nUser-Agent: x\r\nhost: example.com:8080\r\n\r\n").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            host(b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n").as_deref(),
+            Some("::1")
+        );
+        // headers after the request are not the request's
+        assert_eq!(host(b"GET / HTTP/1.1\r\n\r\nHost: example.com\r\n"), None);
+    }
+
+    #[test]
+    fn test_rewrite_host() {
+        let (request, offset) = rewrite_host(
+            b"GET / HTTP/1.1\r\nhost:\texample.com:8080\r\n\r\nHost: body",
+            "www.speedtest.net",
+        )
+        .unwrap();
+        assert_eq!(
+            request,
+            b"GET / HTTP/1.1\r\nhost:\twww.speedtest.net.example.com:8080\r\n\r\nHost: body"
+        );
+        assert_eq!(&request[offset..offset + 4], b".exa");
+
+        // already prefixed, only split
+        let original = b"GET / HTTP/1.1\r\nHost: WWW.speedtest.net.example.com\r\n\r\n";
+        let (request, offset) = rewrite_host(original, "www.speedtest.net").unwrap();
+        assert_eq!(request, original);
+        assert_eq!(&request[offset..offset + 4], b".exa");
+
+        assert_eq!(
+            rewrite_host(b"GET / HTTP/1.1\r\n\r\n", "www.speedtest.net"),
+            None
+        );
+        assert_eq!(
+            rewrite_host(b"GET / HTTP/1.1\r\nHost: x", "www.speedtest.net"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_host_prefix() {
+        let mut downloaded = Vec::new();
+        let mut client = join(
+            Fragments(vec![
+                b"POST / HTTP/1.1\r\nHo".to_vec(),
+                b"st: example.com\r\nContent-Length: 3\r\n\r\nab".to_vec(),
+                b"cGET /2 HTTP/1.1\r\nHost: example.com\r\n\r\n".to_vec(),
+            ]),
+            &mut downloaded,
+        );
+
+        let mut uploaded = Fragments::default();
+        let mut server = join(Nothing, &mut uploaded);
+
+        let patterns = SplitPatterns::new(
+            &[],
+            &[],
+            &[],
+            &[],
+            None,
+            Some("www.speedtest.net".to_owned()),
+        )
+        .unwrap();
+        let sleep_count = process_connection(
+            &mut client,
+            &mut server,
+            &patterns,
+            &no_sleep(),
+            Direction::Up,
+            None,
+        )
+        .await
+        .unwrap();
+
+        // the head is held back until it is complete, the body is not rewritten
         assert_eq!(
             uploaded,
             vec![
-                b"Host: www.".to_vec(),
-                b"speedtest.net".to_vec(),
-                b"hellowww.".to_vec(),
-                b"speedtest.net".to_vec(),
-                b".example.com".to_vec(),
+                b"POST / HTTP/1.1\r\nHost: www.speedtest.net".to_vec(),
+                b".example.com\r\nContent-Length: 3\r\n\r\nab".to_vec(),
+                b"cGET /2 HTTP/1.1\r\nHost: www.speedtest.net".to_vec(),
+                b".example.com\r\n\r\n".to_vec(),
             ]
         );
         assert_eq!(sleep_count, 2);
     }
+
+    #[test]
+    fn test_host_rewriter() {
+        let rewrite = |stream: &[u8]| {
+            // the result must not depend on how the stream is read
+            let mut results = (0..=stream.len()).map(|at| {
+                let mut rewriter = HostRewriter::new("decoy");
+                let (first, second) = stream.split_at(at);
+                let (mut output, mut offsets) = rewriter.feed(first);
+                let (rest, rest_offsets) = rewriter.feed(second);
+                offsets.extend(rest_offsets.iter().map(|offset| offset + output.len()));
+                output.extend(rest);
+                output.extend(rewriter.finish());
+                (output, offsets)
+            });
+            let first = results.next().unwrap();
+            assert!(results.all(|result| result == first));
+            first
+        };
+
+        let (output, offsets) = rewrite(
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5;x=y\r\nHost:\r\n0\r\nTrailer: 1\r\n\r\n\
+              PUT / HTTP/1.0\r\nContent-Length: 9\r\nhost: b\r\n\r\nHost: c\r\n\
+              GET / HTTP/1.1\r\nHost: d\r\n\r\n",
+        );
+        assert_eq!(
+            output,
+            b"POST / HTTP/1.1\r\nHost: decoy.a\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5;x=y\r\nHost:\r\n0\r\nTrailer: 1\r\n\r\n\
+              PUT / HTTP/1.0\r\nContent-Length: 9\r\nhost: decoy.b\r\n\r\nHost: c\r\n\
+              GET / HTTP/1.1\r\nHost: decoy.d\r\n\r\n"
+        );
+        let hosts = offsets
+            .iter()
+            .map(|&offset| output[offset - 5..offset + 2].to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(hosts, [b"decoy.a", b"decoy.b", b"decoy.d"]);
+
+        // everything after an upgrade is forwarded unchanged
+        let upgrade = b"GET / HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\n\r\n\
+                        GET / HTTP/1.1\r\nHost: b\r\n\r\n";
+        let (output, offsets) = rewrite(upgrade);
+        assert_eq!(
+            output,
+            [&b"GET / HTTP/1.1\r\nHost: decoy."[..], &upgrade[22..]].concat()
+        );
+        assert_eq!(offsets.len(), 1);
+
+        // a line that never ends is not held back forever
+        let mut rewriter = HostRewriter::new("decoy");
+        let head = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(rewriter.feed(head).0, head);
+        assert!(rewriter.feed(&[b'1'; MAX_HEADER_LEN]).0.is_empty());
+        assert_eq!(rewriter.feed(b"1").0, vec![b'1'; MAX_HEADER_LEN + 1]);
+        assert_eq!(rewriter.feed(b"\r\n").0, b"\r\n");
+
+        // as is anything that is not HTTP/1.x, and an incomplete head
+        let record = build_client_hello(Some("www.example.com"), &[]);
+        assert_eq!(rewrite(&record), (record, Vec::new()));
+        assert_eq!(
+            rewrite(b"GET / HTTP/2\r\nHost: a\r\n\r\n"),
+            (b"GET / HTTP/2\r\nHost: a\r\n\r\n".to_vec(), Vec::new())
+        );
+        assert_eq!(
+            rewrite(b"GET / HTTP/1.1\r\nHost: a"),
+            (b"GET / HTTP/1.1\r\nHost: a".to_vec(), Vec::new())
+        );
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_route() {
+        let routes = [
+            ".speedtest.net.example.com=www.speedtest.net:443"
+                .parse::<Route>()
+                .unwrap(),
+            "plain.example.com=127.0.0.1:80".parse().unwrap(),
+        ];
+
+        let record = build_client_hello(Some("www.speedtest.net.example.com"), &[]);
+        let mut client = Fragments(vec![record[..10].to_vec(), record[10..].to_vec()]);
+        let (upstream, peeked) = route(&mut client, &routes).await.unwrap();
+        assert_eq!(upstream, "www.speedtest.net:443");
+        assert_eq!(peeked, record);
+
+        let mut client = Fragments(vec![
+            b"GET / HTTP/1.1\r\nHost: plain.exa".to_vec(),
+            b"mple.com\r\n\r\nbody".to_vec(),
+        ]);
+        let (upstream, peeked) = route(&mut client, &routes).await.unwrap();
+        assert_eq!(upstream, "127.0.0.1:80");
+        assert_eq!(
+            peeked,
+            b"GET / HTTP/1.1\r\nHost: plain.example.com\r\n\r\nbody"
+        );
+
+        // not an open proxy
+        let record = build_client_hello(Some("www.speedtest.net"), &[]);
+        let mut client = Fragments(vec![record]);
+        let err = route(&mut client, &routes).await.unwrap_err();
+        assert!(err.to_string().contains("www.speedtest.net"));
+
+        let mut client = Fragments(vec![build_client_hello(None, &[])]);
+        assert!(route(&mut client, &routes).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_sni_split() {
+        assert_eq!("middle".parse::<SniSplit>().unwrap(), SniSplit::Middle);
+        assert_eq!("end".parse::<SniSplit>().unwrap(), SniSplit::End);
+        assert_eq!("3".parse::<SniSplit>().unwrap(), SniSplit::Offset(3));
+        assert!("-1".parse::<SniSplit>().is_err());
+    }
 }
@@ -0,0 +1,325 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Error};
+use rustls::client::{EchMode, Resumption};
+use rustls::pki_types::ServerName;
+use rustls::version::{TLS12, TLS13};
+use rustls::SupportedProtocolVersion;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use crate::ech::{Ech, EchRotation};
+use crate::pin::{self, Pin};
+use crate::resolver::Resolver;
+use crate::{TlsCli, TlsVersion};
+
+pub async fn main(args: TlsCli) -> Result<(), Error> {
+    let addr = format!("{}:{}", args.common.host, args.common.port);
+    tracing::info!("listening on {}, forwarding to {}", addr, args.upstream);
+
+    let (host, upstream) = split_upstream(&args.upstream)?;
+    let resolver = Resolver::new(&args.resolver)?;
+    let server_name = ServerName::try_from(args.sni.clone().unwrap_or(host.clone()))
+        .context("invalid server name")?;
+
+    // rebuilt when the ECH config changes, the session cache is per config
+    let connectors = Arc::new(
+        EchRotation::new(
+            Ech::from_args(&args.ech)?,
+            resolver.clone(),
+            host,
+            move |ech| Ok(TlsConnector::from(Arc::new(client_config(&args, ech)?))),
+        )
+        .await?,
+    );
+
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+
+    loop {
+        let (socket, _) = listener.accept().await.unwrap();
+
+        let resolver = resolver.clone();
+        let upstream = upstream.clone();
+        let connectors = connectors.clone();
+        let server_name = server_name.clone();
+
+        tokio::spawn(async move {
+            tracing::debug!("new connection");
+
+            if let Err(e) =
+                process_connection(socket, &resolver, &upstream, &connectors, server_name).await
+            {
+                tracing::warn!("connection closed, error: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn process_connection(
+    mut socket: TcpStream,
+    resolver: &Resolver,
+    upstream: &str,
+    connectors: &EchRotation<TlsConnector>,
+    server_name: ServerName<'static>,
+) -> Result<(), Error> {
+    let connector = connectors.current().await?;
+    let stream = resolver.connect(upstream).await?;
+    stream.set_nodelay(true)?;
+
+    let mut stream = connector
+        .connect(server_name, stream)
+        .await
+        .context("TLS handshake failed")?;
+
+    // all of these are known once the handshake is done
+    let (_, conn) = stream.get_ref();
+    tracing::debug!(
+        "connected to {}, {:?} {:?}, ALPN {}, {:?} handshake",
+        upstream,
+        conn.protocol_version().unwrap(),
+        conn.negotiated_cipher_suite().unwrap().suite(),
+        conn.alpn_protocol()
+            .map_or("none".into(), String::from_utf8_lossy),
+        conn.handshake_kind().unwrap(),
+    );
+
+    tokio::io::copy_bidirectional(&mut socket, &mut stream).await?;
+    Ok(())
+}
+
+/// Split an upstream into the hostname (for SNI and certificate verification) and host:port
+/// with the default port 443 filled in.
+fn split_upstream(upstream: &str) -> Result<(String, String), Error> {
+    let invalid = || format!("invalid port in {}", upstream);
+    let (host, port) = match upstream.strip_prefix('[') {
+        Some(rest) => {
+            let (host, rest) = rest
+                .split_once(']')
+                .with_context(|| format!("missing ] in {}", upstream))?;
+            match rest {
+                "" => (host, "443"),
+                _ => (host, rest.strip_prefix(':').with_context(invalid)?),
+            }
+        }
+        // a bare IPv6 address, the port can only be given with brackets
+        None if upstream.matches(':').count() > 1 => (upstream, "443"),
+        None => upstream.split_once(':').unwrap_or((upstream, "443")),
+    };
+    let port: u16 = port.parse().with_context(invalid)?;
+
+    Ok((host.to_owned(), format!("{}:{}", host, port)))
+}
+
+/// Build the rustls config from the commandline. Cipher suites and key exchange groups are
+/// offered in the order they are given.
+fn client_config(args: &TlsCli, ech: Option<EchMode>) -> Result<rustls::ClientConfig, Error> {
+    let mut provider = rustls::crypto::aws_lc_rs::default_provider();
+    if !args.ciphers.is_empty() {
+        provider.cipher_suites = select(
+            "cipher suite",
+            &provider.cipher_suites,
+            &args.ciphers,
+            |suite| format!("{:?}", suite.suite()),
+        )?;
+    }
+    if !args.curves.is_empty() {
+        provider.kx_groups = select(
+            "key exchange group",
+            &provider.kx_groups,
+            &args.curves,
+            |group| format!("{:?}", group.name()),
+        )?;
+    }
+    let provider = Arc::new(provider);
+
+    let versions = protocol_versions(args.tls_min, args.tls_max)?;
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone());
+    let builder = match ech {
+        Some(ech) => {
+            anyhow::ensure!(
+                versions.contains(&&TLS13),
+                "ECH requires TLS 1.3, but --tls-max excludes it"
+            );
+            builder.with_ech(ech)?
+        }
+        None => builder.with_protocol_versions(&versions)?,
+    };
+
+    let pins = args
+        .pinning
+        .pin
+        .iter()
+        .map(|pin| Pin::parse(pin))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut config = builder
+        .dangerous()
+        .with_custom_certificate_verifier(pin::server_verifier(pins, args.insecure, provider))
+        .with_no_client_auth();
+
+    config.alpn_protocols = args
+        .alpn
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect();
+    config.enable_sni = !args.no_sni;
+    if args.no_resumption {
+        config.resumption = Resumption::disabled();
+    }
+
+    Ok(config)
+}
+
+/// Pick the items with the given names, in the order of the names. Names are compared
+/// case-insensitively.
+fn select<T: Copy>(
+    kind: &str,
+    available: &[T],
+    names: &[String],
+    name_of: impl Fn(&T) -> String,
+) -> Result<Vec<T>, Error> {
+    names
+        .iter()
+        .map(|name| {
+            available
+                .iter()
+                .find(|item| name_of(item).eq_ignore_ascii_case(name))
+                .copied()
+                .with_context(|| {
+                    let names = available.iter().map(&name_of).collect::<Vec<_>>();
+                    format!("unknown {} {}, available: {}", kind, name, names.join(","))
+                })
+        })
+        .collect()
+}
+
+fn protocol_versions(
+    min: Option<TlsVersion>,
+    max: Option<TlsVersion>,
+) -> Result<Vec<&'static SupportedProtocolVersion>, Error> {
+    let supported = |version| match version {
+        TlsVersion::Tls1_0 | TlsVersion::Tls1_1 => {
+            anyhow::bail!("rustls only supports TLS 1.2 and 1.3")
+        }
+        TlsVersion::Tls1_2 => Ok(0),
+        TlsVersion::Tls1_3 => Ok(1),
+    };
+
+    let min = min.map(supported).transpose()?.unwrap_or(0);
+    let max = max.map(supported).transpose()?.unwrap_or(1);
+    let versions = [&TLS12, &TLS13][min..=max].to_vec();
+    anyhow::ensure!(!versions.is_empty(), "--tls-min is higher than --tls-max");
+    Ok(versions)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::client_hello::{ClientHello, RECORD_HEADER_LEN};
+    use crate::{Cli, CliSubcommand};
+
+    fn parse_args(args: &[&str]) -> TlsCli {
+        let cli = Cli::parse_from([&["minidialer", "tls"], args].concat());
+        let CliSubcommand::Tls(args) = cli.command else {
+            unreachable!()
+        };
+        args
+    }
+
+    /// Send a handshake with the given options to a listener that only reads the ClientHello.
+    async fn capture_client_hello(args: &[&str]) -> Vec<u8> {
+        let args = parse_args(args);
+        let (host, _) = split_upstream(&args.upstream).unwrap();
+        let connector = TlsConnector::from(Arc::new(client_config(&args, None).unwrap()));
+        let server_name = ServerName::try_from(args.sni.clone().unwrap_or(host)).unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut socket, _) = listener.accept().await.unwrap();
+        // the handshake never completes, the listener drops the connection
+        let handshake = tokio::spawn(connector.connect(server_name, stream));
+
+        let mut record = vec![0; RECORD_HEADER_LEN];
+        socket.read_exact(&mut record).await.unwrap();
+        let len = u16::from_be_bytes([record[3], record[4]]) as usize;
+        record.resize(RECORD_HEADER_LEN + len, 0);
+        socket
+            .read_exact(&mut record[RECORD_HEADER_LEN..])
+            .await
+            .unwrap();
+        drop(socket);
+        handshake.await.unwrap().unwrap_err();
+
+        record
+    }
+
+    #[test]
+    fn test_split_upstream() {
+        let split = |upstream| split_upstream(upstream).unwrap();
+        assert_eq!(
+            split("example.com"),
+            ("example.com".to_owned(), "example.com:443".to_owned())
+        );
+        assert_eq!(
+            split("[::1]:8443"),
+            ("::1".to_owned(), "::1:8443".to_owned())
+        );
+        assert_eq!(split("[::1]"), ("::1".to_owned(), "::1:443".to_owned()));
+        assert_eq!(split("::1"), ("::1".to_owned(), "::1:443".to_owned()));
+        assert_eq!(
+            split("example.com:8443"),
+            ("example.com".to_owned(), "example.com:8443".to_owned())
+        );
+        assert!(split_upstream("example.com:https").is_err());
+        assert!(split_upstream("[::1]8443").is_err());
+        assert!(split_upstream("[::1:8443").is_err());
+    }
+
+    #[test]
+    fn test_protocol_versions() {
+        assert_eq!(protocol_versions(None, None).unwrap(), vec![&TLS12, &TLS13]);
+        assert_eq!(
+            protocol_versions(Some(TlsVersion::Tls1_3), None).unwrap(),
+            vec![&TLS13]
+        );
+        assert!(protocol_versions(Some(TlsVersion::Tls1_0), None).is_err());
+        assert!(protocol_versions(Some(TlsVersion::Tls1_3), Some(TlsVersion::Tls1_2)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_hello() {
+        let record = capture_client_hello(&[
+            "--ciphers",
+            "TLS13_CHACHA20_POLY1305_SHA256,tls13_aes_128_gcm_sha256",
+            "--curves",
+            "secp256r1,X25519",
+            "--alpn",
+            "h2,http/1.1",
+            "--sni",
+            "front.example.com",
+            "--tls-min",
+            "1.3",
+            "example.com:443",
+        ])
+        .await;
+        let hello = ClientHello::parse(&record).unwrap();
+
+        assert_eq!(hello.sni(&record), Some("front.example.com"));
+        // without the TLS_EMPTY_RENEGOTIATION_INFO_SCSV rustls adds
+        assert_eq!(&hello.cipher_suites[..2], &[0x1303, 0x1301]);
+        assert_eq!(hello.supported_groups, vec![0x0017, 0x001d]);
+        assert_eq!(hello.alpn, vec![b"h2".to_vec(), b"http/1.1".to_vec()]);
+        assert_eq!(hello.supported_versions, vec![0x0304]);
+
+        let record = capture_client_hello(&["--no-sni", "example.com"]).await;
+        let hello = ClientHello::parse(&record).unwrap();
+        assert_eq!(hello.sni(&record), None);
+        assert!(hello.alpn.is_empty());
+
+        assert!(client_config(&parse_args(&["--ciphers", "RC4", "example.com"]), None).is_err());
+    }
+}